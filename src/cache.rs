@@ -0,0 +1,189 @@
+//! A cache of the last known battery levels, to ride out devices briefly disappearing from BlueZ.
+//!
+//! The cache is a text file with one device per line, as tab-separated fields: the address, the
+//! time it was last seen (in seconds since the Unix epoch), its battery level, icon and name.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{Device, Icon};
+
+pub struct Cache {
+    path: PathBuf,
+    ttl: Duration,
+    entries: HashMap<String, Entry>,
+}
+
+struct Entry {
+    name: String,
+    icon: String,
+    power: u64,
+    seen: SystemTime,
+}
+
+impl Cache {
+    /// Load the cache from `path`, dropping entries older than `ttl`. A missing file is treated
+    /// as an empty cache.
+    pub fn load(path: &Path, ttl: Duration) -> std::io::Result<Self> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+
+        let mut cache = Self {
+            path: path.to_path_buf(),
+            ttl,
+            // Lines that can't be parsed are skipped, so a corrupt cache is only ever a cache miss.
+            entries: contents.lines().filter_map(parse_line).collect(),
+        };
+        cache.evict();
+
+        Ok(cache)
+    }
+
+    /// Record the devices that currently have a battery reading.
+    ///
+    /// Returns `true` if anything other than the last-seen times changed.
+    pub fn update(&mut self, devices: &[Device]) -> bool {
+        let now = SystemTime::now();
+        let mut changed = self.evict();
+
+        for device in devices.iter().filter(|d| d.connected) {
+            let Some(power) = device.power else {
+                continue;
+            };
+            let entry = Entry {
+                name: device.name.clone(),
+                icon: device.icon.0.clone(),
+                power,
+                seen: now,
+            };
+
+            match self.entries.get_mut(&device.address) {
+                Some(old) => {
+                    changed |=
+                        old.name != entry.name || old.icon != entry.icon || old.power != power;
+                    *old = entry;
+                }
+                None => {
+                    self.entries.insert(device.address.clone(), entry);
+                    changed = true;
+                }
+            }
+        }
+
+        changed
+    }
+
+    /// Fill in devices that are missing or have no battery reading with their cached values.
+    ///
+    /// Only addresses for which `wanted` returns `true` are filled in.
+    pub fn fill(&self, devices: &mut Vec<Device>, wanted: impl Fn(&str) -> bool) {
+        let now = SystemTime::now();
+        for (address, entry) in &self.entries {
+            if !wanted(address) || devices.iter().any(|d| &d.address == address && is_live(d)) {
+                continue;
+            }
+
+            devices.retain(|d| &d.address != address);
+            devices.push(Device {
+                name: entry.name.clone(),
+                icon: Icon(entry.icon.clone()),
+                power: Some(entry.power),
+                connected: false,
+                address: address.clone(),
+                rssi: None,
+                source: None,
+                level: None,
+                trend: None,
+                charging: None,
+                age: Some(now.duration_since(entry.seen).unwrap_or_default()),
+                seen: entry.seen,
+            });
+        }
+    }
+
+    pub fn save(&self) -> std::io::Result<()> {
+        let mut addresses = self.entries.keys().collect::<Vec<_>>();
+        addresses.sort_unstable();
+
+        let mut contents = String::new();
+        for address in addresses {
+            let entry = &self.entries[address];
+            let seen = entry.seen.duration_since(UNIX_EPOCH).unwrap_or_default();
+            contents.push_str(&format!(
+                "{address}\t{}\t{}\t{}\t{}\n",
+                seen.as_secs(),
+                entry.power,
+                escape(&entry.icon),
+                escape(&entry.name)
+            ));
+        }
+
+        if let Some(dir) = self.path.parent().filter(|x| !x.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(&self.path, contents)
+    }
+
+    /// Drop entries older than the TTL, returning `true` if any were dropped.
+    fn evict(&mut self) -> bool {
+        let now = SystemTime::now();
+        let len = self.entries.len();
+        self.entries.retain(|_, entry| {
+            now.duration_since(entry.seen)
+                .is_ok_and(|age| age <= self.ttl)
+        });
+        self.entries.len() != len
+    }
+}
+
+/// Whether the device's battery level came from BlueZ just now, rather than from the cache.
+fn is_live(device: &Device) -> bool {
+    device.connected && device.power.is_some()
+}
+
+fn parse_line(line: &str) -> Option<(String, Entry)> {
+    let mut fields = line.splitn(5, '\t');
+    let address = fields.next()?.to_string();
+    let seen = UNIX_EPOCH + Duration::from_secs(fields.next()?.parse().ok()?);
+    let power = fields.next()?.parse().ok()?;
+    let icon = unescape(fields.next()?);
+    let name = unescape(fields.next()?);
+
+    Some((
+        address,
+        Entry {
+            name,
+            icon,
+            power,
+            seen,
+        },
+    ))
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            Some(c) => out.push(c),
+            None => out.push('\\'),
+        }
+    }
+    out
+}
@@ -0,0 +1,474 @@
+//! The optional configuration file.
+//!
+//! This is read from `$XDG_CONFIG_HOME/bluetooth-battery/config.toml`, falling back to
+//! `~/.config/bluetooth-battery/config.toml`. Only the subset of TOML that the configuration
+//! needs is understood: tables, and keys with string or integer values or single-line arrays of
+//! strings.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Default)]
+pub struct Config {
+    /// Extra or overridden icons, keyed by freedesktop icon name.
+    pub icons: HashMap<String, IconConfig>,
+    /// Names to show instead of the ones from BlueZ, keyed by upper case address.
+    pub aliases: HashMap<String, String>,
+    /// Upper case addresses in the order `--pin-order` shows them.
+    pub order: Vec<String>,
+    /// The format to show devices in, keyed by freedesktop icon name, instead of the one from
+    /// the command line.
+    pub formats: HashMap<String, crate::DeviceFormat>,
+    /// Battery levels at which devices are low, keyed by freedesktop icon name, instead of
+    /// `--warn` and `--crit`.
+    pub thresholds: HashMap<String, IconThresholds>,
+}
+
+/// The thresholds for one kind of device. Any that aren't set come from the command line.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct IconThresholds {
+    pub warn: Option<u64>,
+    pub crit: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct IconConfig {
+    pub emoji: Option<String>,
+    /// The name of a Material Symbols glyph, e.g. `watch`.
+    pub material_symbols: Option<String>,
+}
+
+/// The directory the configuration lives in, e.g. `~/.config/bluetooth-battery`.
+fn dir() -> Option<PathBuf> {
+    let dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|x| !x.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|x| PathBuf::from(x).join(".config")))?;
+    Some(dir.join(env!("CARGO_PKG_NAME")))
+}
+
+impl Config {
+    pub fn path() -> Option<PathBuf> {
+        Some(dir()?.join("config.toml"))
+    }
+
+    /// Load the configuration file, or the default configuration if there isn't one.
+    pub fn load() -> Result<Self, Error> {
+        match Self::path() {
+            Some(path) => Self::load_from(&path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    pub fn load_from(path: &Path) -> Result<Self, Error> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(Error::Io(path.to_path_buf(), e)),
+        };
+
+        contents
+            .parse()
+            .map_err(|e| Error::Parse(path.to_path_buf(), e))
+    }
+}
+
+impl std::str::FromStr for Config {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = Self::default();
+
+        for entry in parse(s)? {
+            let table = entry.table.iter().map(String::as_str).collect::<Vec<_>>();
+            match (table.as_slice(), entry.key.as_str()) {
+                (["icons", name], "emoji") => {
+                    config.icons.entry(name.to_string()).or_default().emoji = Some(entry.string()?);
+                }
+                (["icons", name], "material_symbols") => {
+                    config
+                        .icons
+                        .entry(name.to_string())
+                        .or_default()
+                        .material_symbols = Some(entry.string()?);
+                }
+                (["aliases"], address) => {
+                    config
+                        .aliases
+                        .insert(address.to_ascii_uppercase(), entry.string()?);
+                }
+                (["formats"], icon) => {
+                    let format = entry.string()?.parse().map_err(|e| entry.error(e))?;
+                    config.formats.insert(icon.to_string(), format);
+                }
+                (["thresholds", icon], "warn") => {
+                    config.thresholds.entry(icon.to_string()).or_default().warn =
+                        Some(entry.percent()?);
+                }
+                (["thresholds", icon], "crit") => {
+                    config.thresholds.entry(icon.to_string()).or_default().crit =
+                        Some(entry.percent()?);
+                }
+                ([], "order") => {
+                    config.order = entry
+                        .strings()?
+                        .iter()
+                        .map(|x| x.to_ascii_uppercase())
+                        .collect();
+                }
+                _ => return Err(entry.error("unknown key")),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// The devices to show when none are given on the command line, read from a file with one
+/// `ADDRESS [= ALIAS]` per line. Blank lines and lines starting with `#` are ignored.
+#[derive(Debug, Default)]
+pub struct DeviceList {
+    /// Upper case addresses, in the order they're listed.
+    pub addresses: Vec<String>,
+    /// Names to show instead of the ones from BlueZ, keyed by upper case address.
+    pub aliases: HashMap<String, String>,
+}
+
+impl DeviceList {
+    pub fn path() -> Option<PathBuf> {
+        Some(dir()?.join("devices"))
+    }
+
+    /// Load the list from its default path, or an empty one if there's no such file.
+    pub fn load() -> Result<Self, Error> {
+        match Self::path() {
+            Some(path) => Self::load_or_default(&path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    /// Load the list from `path`, or an empty one if there's no such file.
+    pub fn load_or_default(path: &Path) -> Result<Self, Error> {
+        match Self::load_from(path) {
+            Err(Error::Io(_, e)) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            x => x,
+        }
+    }
+
+    pub fn load_from(path: &Path) -> Result<Self, Error> {
+        std::fs::read_to_string(path)
+            .map_err(|e| Error::Io(path.to_path_buf(), e))?
+            .parse()
+            .map_err(|e| Error::Parse(path.to_path_buf(), e))
+    }
+}
+
+impl std::str::FromStr for DeviceList {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut list = Self::default();
+
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (address, alias) = match line.split_once('=') {
+                Some((address, alias)) => (address.trim_end(), Some(alias.trim_start())),
+                None => (line, None),
+            };
+            if !crate::is_valid_address(address) {
+                return Err(ParseError {
+                    line: i + 1,
+                    message: format!("invalid bluetooth address: {address}"),
+                });
+            }
+
+            let address = address.to_ascii_uppercase();
+            if let Some(alias) = alias.filter(|x| !x.is_empty()) {
+                list.aliases.insert(address.clone(), alias.to_string());
+            }
+            list.addresses.push(address);
+        }
+
+        Ok(list)
+    }
+}
+
+/// Glyphs to show for icons, e.g. from a particular icon theme, read from a file with one
+/// `ICON = GLYPH` per line. Blank lines and lines starting with `#` are ignored.
+#[derive(Debug, Default)]
+pub struct IconMap {
+    /// The glyph for each freedesktop icon name, used as is.
+    pub glyphs: HashMap<String, String>,
+}
+
+impl IconMap {
+    pub fn load_from(path: &Path) -> Result<Self, Error> {
+        std::fs::read_to_string(path)
+            .map_err(|e| Error::Io(path.to_path_buf(), e))?
+            .parse()
+            .map_err(|e| Error::Parse(path.to_path_buf(), e))
+    }
+}
+
+impl std::str::FromStr for IconMap {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut map = Self::default();
+
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let Some((icon, glyph)) = line
+                .split_once('=')
+                .map(|(icon, glyph)| (icon.trim_end(), glyph.trim_start()))
+                .filter(|(icon, glyph)| !icon.is_empty() && !glyph.is_empty())
+            else {
+                return Err(ParseError {
+                    line: i + 1,
+                    message: "expected ICON = GLYPH".to_string(),
+                });
+            };
+            map.glyphs.insert(icon.to_string(), glyph.to_string());
+        }
+
+        Ok(map)
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, ParseError),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(path, e) => write!(f, "failed to read {}: {e}", path.display()),
+            Self::Parse(path, e) => write!(f, "{}:{e}", path.display()),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Debug)]
+pub struct ParseError {
+    line: usize,
+    message: String,
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Array(Vec<String>),
+}
+
+/// A `key = value` pair, along with the table it belongs to.
+#[derive(Debug)]
+struct Entry {
+    table: Vec<String>,
+    key: String,
+    value: Value,
+    line: usize,
+}
+
+impl Entry {
+    fn error(&self, message: &str) -> ParseError {
+        let key = self
+            .table
+            .iter()
+            .chain(std::iter::once(&self.key))
+            .cloned()
+            .collect::<Vec<_>>()
+            .join(".");
+        ParseError {
+            line: self.line,
+            message: format!("{message}: {key}"),
+        }
+    }
+
+    fn string(&self) -> Result<String, ParseError> {
+        match &self.value {
+            Value::String(x) => Ok(x.clone()),
+            _ => Err(self.error("expected a string")),
+        }
+    }
+
+    /// An integer from 0 to 100.
+    fn percent(&self) -> Result<u64, ParseError> {
+        match self.value {
+            Value::Integer(x @ 0..=100) => Ok(x as u64),
+            _ => Err(self.error("expected an integer from 0 to 100")),
+        }
+    }
+
+    fn strings(&self) -> Result<Vec<String>, ParseError> {
+        match &self.value {
+            Value::Array(x) => Ok(x.clone()),
+            _ => Err(self.error("expected an array")),
+        }
+    }
+}
+
+fn parse(s: &str) -> Result<Vec<Entry>, ParseError> {
+    let mut entries = vec![];
+    let mut table = vec![];
+
+    for (i, line) in s.lines().enumerate() {
+        let line_number = i + 1;
+        let error = |message: &str| ParseError {
+            line: line_number,
+            message: message.to_string(),
+        };
+
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(rest) = line.strip_prefix('[') {
+            let mut rest = rest.trim_start();
+            table.clear();
+            loop {
+                let (key, tail) = parse_key(rest).ok_or_else(|| error("invalid table name"))?;
+                table.push(key);
+                rest = tail.trim_start();
+                if let Some(tail) = rest.strip_prefix('.') {
+                    rest = tail.trim_start();
+                } else if let Some(tail) = rest.strip_prefix(']') {
+                    rest = tail;
+                    break;
+                } else {
+                    return Err(error("expected '.' or ']'"));
+                }
+            }
+            if !is_comment_or_empty(rest) {
+                return Err(error("unexpected characters after table name"));
+            }
+            continue;
+        }
+
+        let (key, rest) = parse_key(line).ok_or_else(|| error("invalid key"))?;
+        let rest = rest
+            .trim_start()
+            .strip_prefix('=')
+            .ok_or_else(|| error("expected '='"))?
+            .trim_start();
+        let (value, rest) = parse_value(rest).ok_or_else(|| error("invalid value"))?;
+        if !is_comment_or_empty(rest) {
+            return Err(error("unexpected characters after value"));
+        }
+
+        entries.push(Entry {
+            table: table.clone(),
+            key,
+            value,
+            line: line_number,
+        });
+    }
+
+    Ok(entries)
+}
+
+fn is_comment_or_empty(s: &str) -> bool {
+    let s = s.trim_start();
+    s.is_empty() || s.starts_with('#')
+}
+
+/// Parse a bare or quoted key, returning it and the rest of the input.
+fn parse_key(s: &str) -> Option<(String, &str)> {
+    if s.starts_with(['"', '\'']) {
+        return parse_string(s);
+    }
+
+    let end = s
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+        .unwrap_or(s.len());
+    (end > 0).then(|| (s[..end].to_string(), &s[end..]))
+}
+
+fn parse_value(s: &str) -> Option<(Value, &str)> {
+    if s.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '+') {
+        let end = s
+            .find(|c: char| c.is_ascii_whitespace() || c == '#')
+            .unwrap_or(s.len());
+        let x = s[..end].replace('_', "").parse().ok()?;
+        return Some((Value::Integer(x), &s[end..]));
+    }
+    let Some(mut rest) = s.strip_prefix('[') else {
+        return parse_string(s).map(|(x, rest)| (Value::String(x), rest));
+    };
+
+    let mut values = vec![];
+    loop {
+        rest = rest.trim_start();
+        if let Some(tail) = rest.strip_prefix(']') {
+            return Some((Value::Array(values), tail));
+        }
+        let (value, tail) = parse_string(rest)?;
+        values.push(value);
+        rest = tail.trim_start();
+        if let Some(tail) = rest.strip_prefix(',') {
+            rest = tail;
+        } else if !rest.starts_with(']') {
+            return None;
+        }
+    }
+}
+
+/// Parse a basic (`"..."`) or literal (`'...'`) string, returning it and the rest of the input.
+fn parse_string(s: &str) -> Option<(String, &str)> {
+    if let Some(rest) = s.strip_prefix('\'') {
+        let end = rest.find('\'')?;
+        return Some((rest[..end].to_string(), &rest[end + 1..]));
+    }
+
+    let mut out = String::new();
+    let mut chars = s.strip_prefix('"')?.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((out, &s[i + 2..])),
+            '\\' => {
+                let c = match chars.next()?.1 {
+                    'b' => '\u{8}',
+                    't' => '\t',
+                    'n' => '\n',
+                    'f' => '\u{c}',
+                    'r' => '\r',
+                    '"' => '"',
+                    '\\' => '\\',
+                    x @ ('u' | 'U') => {
+                        let len = if x == 'u' { 4 } else { 8 };
+                        let hex = (0..len)
+                            .map(|_| chars.next().map(|(_, c)| c))
+                            .collect::<Option<String>>()?;
+                        char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+                    }
+                    _ => return None,
+                };
+                out.push(c);
+            }
+            c => out.push(c),
+        }
+    }
+
+    None
+}
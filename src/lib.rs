@@ -0,0 +1,2447 @@
+//! Query the battery level of connected bluetooth devices from BlueZ over DBus, and format it
+//! for status bars.
+
+pub mod cache;
+pub mod config;
+pub mod log;
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, SystemTime};
+
+use dbus::Path;
+use dbus::arg::{PropMap, RefArg};
+use dbus::blocking::Connection;
+use dbus::blocking::stdintf::org_freedesktop_dbus::{ObjectManager, Properties};
+
+/// The objects returned by `org.freedesktop.DBus.ObjectManager.GetManagedObjects`.
+pub type ManagedObjects = HashMap<Path<'static>, HashMap<String, PropMap>>;
+
+/// Objects that couldn't be read, by path, along with why.
+pub type Failures = Vec<(String, dbus::Error)>;
+
+/// Where devices and their properties come from: BlueZ on the system bus, or fake data in tests.
+pub trait BatterySource {
+    /// Every object BlueZ exports, as returned by
+    /// `org.freedesktop.DBus.ObjectManager.GetManagedObjects`.
+    fn managed_objects(&self) -> Result<ManagedObjects, dbus::Error>;
+
+    /// A single property of the object at `path`, as returned by
+    /// `org.freedesktop.DBus.Properties.Get`.
+    fn get_property(
+        &self,
+        path: &str,
+        interface: &str,
+        name: &str,
+    ) -> Result<Box<dyn RefArg>, dbus::Error>;
+
+    /// Every property of one interface of the object at `path`, as returned by
+    /// `org.freedesktop.DBus.Properties.GetAll`.
+    fn get_all(&self, path: &str, interface: &str) -> Result<PropMap, dbus::Error>;
+
+    /// The paths of the objects directly below `path`, from
+    /// `org.freedesktop.DBus.Introspectable.Introspect`.
+    fn child_paths(&self, path: &str) -> Result<Vec<String>, dbus::Error>;
+
+    /// Batteries from UPower, keyed by upper case address, for devices that report them there
+    /// instead of (or as well as) to BlueZ. Sources without UPower have none.
+    fn upower_levels(&self) -> Result<HashMap<String, UPowerBattery>, dbus::Error> {
+        Ok(HashMap::new())
+    }
+}
+
+/// What UPower knows about a device's battery.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UPowerBattery {
+    pub power: Option<u64>,
+    /// Whether UPower's `State` is charging. BlueZ itself doesn't say.
+    pub charging: bool,
+}
+
+/// The well-known name BlueZ owns on the system bus.
+pub const BLUEZ_SERVICE: &str = "org.bluez";
+
+/// BlueZ, reached over a DBus connection (usually the system bus).
+pub struct BlueZ<'a> {
+    conn: &'a Connection,
+    timeout: Duration,
+    service: String,
+}
+
+impl<'a> BlueZ<'a> {
+    pub fn new(conn: &'a Connection, timeout: Duration) -> Self {
+        Self {
+            conn,
+            timeout,
+            service: BLUEZ_SERVICE.to_string(),
+        }
+    }
+
+    /// Talk to `service` instead of [`BLUEZ_SERVICE`], e.g. a stub standing in for BlueZ in
+    /// tests.
+    pub fn with_service(mut self, service: &str) -> Self {
+        self.service = service.to_string();
+        self
+    }
+}
+
+impl BatterySource for BlueZ<'_> {
+    fn managed_objects(&self) -> Result<ManagedObjects, dbus::Error> {
+        self.conn
+            .with_proxy(self.service.as_str(), "/", self.timeout)
+            .get_managed_objects()
+    }
+
+    fn get_property(
+        &self,
+        path: &str,
+        interface: &str,
+        name: &str,
+    ) -> Result<Box<dyn RefArg>, dbus::Error> {
+        self.conn
+            .with_proxy(self.service.as_str(), path, self.timeout)
+            .get(interface, name)
+    }
+
+    fn get_all(&self, path: &str, interface: &str) -> Result<PropMap, dbus::Error> {
+        self.conn
+            .with_proxy(self.service.as_str(), path, self.timeout)
+            .get_all(interface)
+    }
+
+    fn child_paths(&self, path: &str) -> Result<Vec<String>, dbus::Error> {
+        let (xml,): (String,) = self
+            .conn
+            .with_proxy(self.service.as_str(), path, self.timeout)
+            .method_call("org.freedesktop.DBus.Introspectable", "Introspect", ())?;
+        Ok(child_nodes(&xml)
+            .map(|x| format!("{}/{x}", path.trim_end_matches('/')))
+            .collect())
+    }
+
+    fn upower_levels(&self) -> Result<HashMap<String, UPowerBattery>, dbus::Error> {
+        let proxy = |path| {
+            self.conn
+                .with_proxy("org.freedesktop.UPower", path, self.timeout)
+        };
+        let (paths,): (Vec<Path<'static>>,) = proxy(Path::from("/org/freedesktop/UPower"))
+            .method_call("org.freedesktop.UPower", "EnumerateDevices", ())?;
+
+        let mut levels = HashMap::new();
+        for path in paths {
+            // One device UPower can't describe shouldn't hide the levels of the others.
+            let props = match proxy(path.clone()).get_all("org.freedesktop.UPower.Device") {
+                Ok(x) => x,
+                Err(e) => {
+                    debug!(
+                        "skipping UPower device {path}: {}",
+                        e.message().unwrap_or("unknown error")
+                    );
+                    continue;
+                }
+            };
+            let address = ["Serial", "NativePath"]
+                .iter()
+                .filter_map(|x| props.get(*x)?.0.as_str())
+                .find_map(find_address);
+            let power = props
+                .get("Percentage")
+                .and_then(|x| x.0.as_f64())
+                .and_then(upower_percentage);
+            // https://upower.freedesktop.org/docs/Device.html#Device:State
+            let charging = props
+                .get("State")
+                .and_then(|x| x.0.as_u64())
+                .is_some_and(|x| x == 1);
+            if let Some(address) = address {
+                levels.insert(address, UPowerBattery { power, charging });
+            }
+        }
+        Ok(levels)
+    }
+}
+
+/// The names of the `<node>` elements in introspection XML, apart from the root one (which, if it
+/// has a name at all, has an absolute one).
+fn child_nodes(xml: &str) -> impl Iterator<Item = &str> {
+    xml.split("<node name=\"")
+        .skip(1)
+        .filter_map(|x| x.split_once('"'))
+        .map(|(name, _)| name)
+        .filter(|x| !x.is_empty() && !x.starts_with('/'))
+}
+
+/// The battery level from UPower's `Percentage`, which like [`percentage`] is only kept from going
+/// below 0.
+fn upower_percentage(x: f64) -> Option<u64> {
+    x.is_finite().then(|| x.round().max(0.0) as u64)
+}
+
+/// The first bluetooth address in `s`, with colons or underscores between its bytes, e.g. in
+/// UPower's `/org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF` or `hid-aa:bb:cc:dd:ee:ff-battery`.
+fn find_address(s: &str) -> Option<String> {
+    let s = s.replace('_', ":");
+    (0..s.len())
+        .filter_map(|i| s.get(i..i + 17))
+        .find(|x| is_valid_address(x))
+        .map(|x| x.to_ascii_uppercase())
+}
+
+/// Fetch every bluetooth device known to BlueZ, whether or not it's connected or reports a
+/// battery level.
+pub fn fetch_all_devices(source: &impl BatterySource) -> Result<Vec<Device>, dbus::Error> {
+    Ok(devices_from_objects(&source.managed_objects()?, None))
+}
+
+/// Fetch every bluetooth device known to BlueZ by reading each object on its own, for when
+/// `GetManagedObjects` times out because of one slow device.
+///
+/// Only the devices on `adapter` are included if it's given. Devices that can't be read are
+/// skipped, and returned by path along with the error instead.
+pub fn fetch_devices_one_by_one(
+    source: &impl BatterySource,
+    adapter: Option<&str>,
+) -> Result<(Vec<Device>, Failures), dbus::Error> {
+    let mut devices = vec![];
+    let mut failures = vec![];
+
+    for adapter_path in source.child_paths("/org/bluez")? {
+        if adapter.is_some_and(|x| adapter_path != format!("/org/bluez/{x}")) {
+            continue;
+        }
+        let paths = match source.child_paths(&adapter_path) {
+            Ok(x) => x,
+            Err(e) => {
+                failures.push((adapter_path, e));
+                continue;
+            }
+        };
+
+        for path in paths.into_iter().filter(|x| x.contains("/dev_")) {
+            let device = match source.get_all(&path, "org.bluez.Device1") {
+                Ok(x) => x,
+                Err(e) => {
+                    failures.push((path, e));
+                    continue;
+                }
+            };
+            let mut interfaces = HashMap::from([("org.bluez.Device1".to_string(), device)]);
+            match source.get_all(&path, "org.bluez.Battery1") {
+                Ok(battery) => {
+                    interfaces.insert("org.bluez.Battery1".to_string(), battery);
+                }
+                Err(e) if is_timeout(&e) => {
+                    failures.push((path, e));
+                    continue;
+                }
+                // Most likely a device without a battery.
+                Err(_) => {}
+            }
+            devices.extend(device_from_interfaces(&interfaces));
+        }
+    }
+
+    Ok((devices, failures))
+}
+
+/// Build the devices among the objects returned by `GetManagedObjects`, only including those on
+/// `adapter` if it's given.
+pub fn devices_from_objects(objects: &ManagedObjects, adapter: Option<&str>) -> Vec<Device> {
+    objects
+        .iter()
+        .filter(|(path, _)| adapter.is_none_or(|x| is_on_adapter(path, x)))
+        .filter_map(|(_, interfaces)| device_from_interfaces(interfaces))
+        .collect()
+}
+
+/// Whether the object at `path` belongs to `adapter`, e.g. `hci0`.
+pub fn is_on_adapter(path: &str, adapter: &str) -> bool {
+    path.strip_prefix("/org/bluez/")
+        .and_then(|x| x.strip_prefix(adapter))
+        .is_some_and(|x| x.starts_with('/'))
+}
+
+/// The battery level from a `Percentage` property. Negative levels become 0, but levels over 100
+/// are kept as they are, so that they can be seen when debugging (see `--clamp`).
+///
+/// BlueZ sends a byte, but some devices end up with a signed integer or a double instead, which
+/// would otherwise make them disappear.
+fn percentage(value: &dyn RefArg) -> Option<u64> {
+    if let Some(x) = value.as_u64() {
+        return Some(x);
+    }
+
+    let x = value.as_i64().map(|x| x.max(0) as u64).or_else(|| {
+        value
+            .as_f64()
+            .filter(|x| x.is_finite())
+            .map(|x| x.round().max(0.0) as u64)
+    })?;
+    debug!(
+        "converted a Percentage of type {} to {x}",
+        value.signature()
+    );
+    Some(x)
+}
+
+fn rssi(value: &dyn RefArg) -> Option<i16> {
+    value.as_i64().and_then(|x| i16::try_from(x).ok())
+}
+
+/// Fetch a single device by its object path, e.g. from [`device_path`].
+///
+/// The battery level is only fetched if the device is connected. Each interface's properties are
+/// read in one call, so that a device costs at most two round trips.
+pub fn fetch_device(source: &impl BatterySource, path: &str) -> Result<Device, dbus::Error> {
+    let device = source.get_all(path, "org.bluez.Device1")?;
+
+    let connected = property(&device, "Connected")?
+        .as_u64()
+        .is_some_and(|x| x != 0);
+    let (power, source_name) = if connected {
+        let battery = source.get_all(path, "org.bluez.Battery1")?;
+        let power = expect(percentage(property(&battery, "Percentage")?), "Percentage")?;
+        // Only there for batteries from a battery provider, e.g. the HFP plugin.
+        let source_name = battery
+            .get("Source")
+            .and_then(|x| x.0.as_str())
+            .map(String::from);
+        (Some(power), source_name)
+    } else {
+        (None, None)
+    };
+    let name = expect(property(&device, "Name")?.as_str(), "Name")?.to_string();
+    let icon = expect(property(&device, "Icon")?.as_str(), "Icon")?.to_string();
+    let address = expect(property(&device, "Address")?.as_str(), "Address")?.to_string();
+    // Only there while BlueZ is discovering or the device is connected.
+    let rssi = device.get("RSSI").and_then(|x| rssi(&*x.0));
+
+    Ok(Device {
+        name,
+        icon: Icon(icon),
+        power,
+        connected,
+        address,
+        rssi,
+        source: source_name,
+        level: None,
+        trend: None,
+        charging: None,
+        age: None,
+        seen: SystemTime::now(),
+    })
+}
+
+/// A property from a `GetAll` reply, failing like `Get` would if it's missing.
+fn property<'a>(props: &'a PropMap, name: &str) -> Result<&'a dyn RefArg, dbus::Error> {
+    props.get(name).map(|x| &*x.0).ok_or_else(|| {
+        dbus::Error::new_custom(
+            "org.freedesktop.DBus.Error.InvalidArgs",
+            &format!("No such property '{name}'"),
+        )
+    })
+}
+
+/// Fail if a property didn't have the type BlueZ documents.
+fn expect<T>(value: Option<T>, name: &str) -> Result<T, dbus::Error> {
+    value.ok_or_else(|| dbus::Error::new_failed(&format!("unexpected type for {name}")))
+}
+
+/// The object path BlueZ uses for the device with the given address.
+pub fn device_path(adapter: &str, address: &str) -> String {
+    format!(
+        "/org/bluez/{adapter}/dev_{}",
+        address.to_ascii_uppercase().replace(':', "_")
+    )
+}
+
+/// Whether `address` looks like a bluetooth address: six colon-separated pairs of hex digits,
+/// e.g. `AA:BB:CC:DD:EE:FF`.
+pub fn is_valid_address(address: &str) -> bool {
+    let groups = address.split(':').collect::<Vec<_>>();
+    groups.len() == 6
+        && groups
+            .iter()
+            .all(|x| x.len() == 2 && x.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+/// The adapter the user asked for doesn't exist.
+#[derive(Debug)]
+pub struct UnknownAdapter {
+    adapter: String,
+    available: Vec<String>,
+}
+
+impl std::fmt::Display for UnknownAdapter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "no such adapter: {}", self.adapter)?;
+        if self.available.is_empty() {
+            write!(f, " (no adapters found)")
+        } else {
+            write!(f, " (available: {})", self.available.join(", "))
+        }
+    }
+}
+
+impl std::error::Error for UnknownAdapter {}
+
+/// Whether the error means that nothing owns the `org.bluez` name, i.e. BlueZ isn't running.
+pub fn is_service_unknown(e: &dbus::Error) -> bool {
+    matches!(
+        e.name(),
+        Some(
+            "org.freedesktop.DBus.Error.ServiceUnknown"
+                | "org.freedesktop.DBus.Error.NameHasNoOwner"
+        )
+    )
+}
+
+/// Whether the error means that BlueZ didn't answer in time.
+pub fn is_timeout(e: &dbus::Error) -> bool {
+    matches!(
+        e.name(),
+        Some("org.freedesktop.DBus.Error.NoReply" | "org.freedesktop.DBus.Error.Timeout")
+    )
+}
+
+pub fn check_adapter(objects: &ManagedObjects, adapter: &str) -> Result<(), UnknownAdapter> {
+    let mut available = objects
+        .iter()
+        .filter(|(_, interfaces)| interfaces.contains_key("org.bluez.Adapter1"))
+        .filter_map(|(path, _)| path.strip_prefix("/org/bluez/"))
+        .collect::<Vec<_>>();
+
+    if available.contains(&adapter) {
+        return Ok(());
+    }
+
+    available.sort_unstable();
+    Err(UnknownAdapter {
+        adapter: adapter.to_string(),
+        available: available.into_iter().map(String::from).collect(),
+    })
+}
+
+/// The end of an address given by the user didn't match exactly one device.
+#[derive(Debug)]
+pub struct PartialAddress {
+    partial: String,
+    candidates: Vec<String>,
+}
+
+impl std::fmt::Display for PartialAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.candidates.is_empty() {
+            write!(f, "no device's address ends with {}", self.partial)
+        } else {
+            write!(
+                f,
+                "more than one device's address ends with {}: {}",
+                self.partial,
+                self.candidates.join(", ")
+            )
+        }
+    }
+}
+
+impl std::error::Error for PartialAddress {}
+
+/// The full address of the one device on `adapter` whose address ends with `partial`, e.g.
+/// `DD:EE:FF`, ignoring case.
+pub fn resolve_address(
+    objects: &ManagedObjects,
+    adapter: &str,
+    partial: &str,
+) -> Result<String, PartialAddress> {
+    let suffix = format!(":{}", partial.to_ascii_uppercase());
+    let mut candidates = objects
+        .iter()
+        .filter(|(path, _)| is_on_adapter(path, adapter))
+        .filter_map(|(_, interfaces)| {
+            interfaces
+                .get("org.bluez.Device1")?
+                .get("Address")?
+                .0
+                .as_str()
+        })
+        .map(str::to_ascii_uppercase)
+        .filter(|x| x.ends_with(&suffix))
+        .collect::<Vec<_>>();
+
+    if candidates.len() == 1 {
+        return Ok(candidates.remove(0));
+    }
+
+    candidates.sort_unstable();
+    Err(PartialAddress {
+        partial: partial.to_string(),
+        candidates,
+    })
+}
+
+/// Build a device from the interfaces of one object returned by `GetManagedObjects`.
+///
+/// Returns `None` if the object isn't a device, or is missing its address, icon, or both its name
+/// and alias.
+pub fn device_from_interfaces(interfaces: &HashMap<String, PropMap>) -> Option<Device> {
+    let device = interfaces.get("org.bluez.Device1")?;
+    let connected = device
+        .get("Connected")
+        .and_then(|x| x.0.as_u64())
+        .is_some_and(|x| x != 0);
+    let address = device
+        .get("Address")
+        .and_then(|x| x.0.as_str())?
+        .to_string();
+    // BlueZ derives the alias from the name, but it can be the only one set, e.g. for devices
+    // that never reported a name.
+    let name = device
+        .get("Name")
+        .or_else(|| device.get("Alias"))
+        .and_then(|x| x.0.as_str())?
+        .to_string();
+    let icon = device
+        .get("Icon")
+        .and_then(|x| x.0.as_str())?
+        .parse()
+        .ok()?;
+    let battery = interfaces.get("org.bluez.Battery1");
+    let power = battery
+        .and_then(|x| x.get("Percentage"))
+        .and_then(|x| percentage(&*x.0));
+    let source = battery
+        .and_then(|x| x.get("Source"))
+        .and_then(|x| x.0.as_str())
+        .map(String::from);
+    let rssi = device.get("RSSI").and_then(|x| rssi(&*x.0));
+
+    Some(Device {
+        name,
+        icon,
+        power,
+        connected,
+        address,
+        rssi,
+        source,
+        level: None,
+        trend: None,
+        charging: None,
+        age: None,
+        seen: SystemTime::now(),
+    })
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Device {
+    pub name: String,
+    pub icon: Icon,
+    /// The battery level, or `None` if it isn't known, e.g. because the device is disconnected.
+    pub power: Option<u64>,
+    pub connected: bool,
+    /// The device's address, e.g. `AA:BB:CC:DD:EE:FF`.
+    pub address: String,
+    /// The signal strength in dBm, if BlueZ knows it.
+    pub rssi: Option<i16>,
+    /// Where BlueZ got the battery level from, e.g. `HFP`, if it came from a battery provider
+    /// rather than the device's GATT battery service.
+    pub source: Option<String>,
+    /// How low the battery is, if it shouldn't follow the thresholds, e.g. because it's held by
+    /// [`Thresholds::level_after`].
+    pub level: Option<Level>,
+    /// Which way the battery level is going, if it's being followed, e.g. with `--trend`.
+    pub trend: Option<Trend>,
+    /// Whether the battery is charging, if it's known.
+    pub charging: Option<bool>,
+    /// How old the battery level is, if it wasn't read from BlueZ just now.
+    pub age: Option<Duration>,
+    /// When the battery level was read from BlueZ.
+    pub seen: SystemTime,
+}
+
+impl Device {
+    /// A connected device with no address, with its battery level read just now.
+    pub fn new(name: impl Into<String>, icon: impl Into<String>, power: Option<u64>) -> Self {
+        Self {
+            name: name.into(),
+            icon: Icon(icon.into()),
+            power,
+            connected: true,
+            address: String::new(),
+            rssi: None,
+            source: None,
+            level: None,
+            trend: None,
+            charging: None,
+            age: None,
+            seen: SystemTime::now(),
+        }
+    }
+
+    /// Limit the battery level to 100, for devices that report more.
+    pub fn clamp_power(&mut self) {
+        if let Some(power) = &mut self.power {
+            *power = (*power).min(100);
+        }
+    }
+
+    /// Round the battery level to the nearest multiple of `step`, to stop it jittering.
+    pub fn round(&mut self, step: u64) {
+        if let Some(power) = &mut self.power {
+            // Rounding up shouldn't take a level over 100, but one that's already over is left for
+            // `clamp_power`.
+            *power = ((*power + step / 2) / step * step).min((*power).max(100));
+        }
+    }
+
+    pub fn long(&self, style: &Style) -> String {
+        let age = self.age.or_else(|| {
+            style
+                .show_age
+                .then(|| self.seen.elapsed().unwrap_or_default())
+        });
+        let address = if style.show_address {
+            format!("{}, ", self.address)
+        } else {
+            String::new()
+        };
+        let power = self.shown_power(style);
+        let rssi = match self.rssi {
+            Some(rssi) if style.show_rssi => format!(", {rssi}dBm"),
+            _ => String::new(),
+        };
+        let source = match &self.source {
+            Some(source) if style.show_source => format!(", via {source}"),
+            _ => String::new(),
+        };
+        format!(
+            "{}{} ({address}{}{rssi}{source}{})",
+            self.shown_icon(style),
+            self.name,
+            style.power_at(power, self.level(style)) + &self.charging_suffix(style),
+            age.map(|x| format!(", {} ago", format_age(x)))
+                .unwrap_or_default()
+        )
+    }
+
+    pub fn short(&self, style: &Style) -> String {
+        let address = if style.show_address {
+            format!(" ({})", self.address)
+        } else {
+            String::new()
+        };
+        format!(
+            "{}{address} {}{}{}",
+            self.name,
+            percent(self.shown_power(style), &style.numbers),
+            self.charging_suffix(style),
+            self.age_suffix()
+        )
+    }
+
+    pub fn narrow(&self, style: &Style) -> String {
+        let shown = self.shown_power(style);
+        let power = match self.bar().filter(|_| shown.is_some()) {
+            Some(bar) if style.bar && style.show_percent => {
+                format!("{bar} {}", style.power_at(shown, self.level(style)))
+            }
+            Some(bar) if style.bar => bar.to_string(),
+            _ => style.power_at(shown, self.level(style)),
+        };
+        let icon = self.shown_icon(style);
+        let icon = if style.compact {
+            icon.trim_end()
+        } else {
+            &icon
+        };
+        format!(
+            "{icon}{power}{}{}",
+            self.charging_suffix(style),
+            self.age_suffix()
+        )
+    }
+
+    /// Just the icon, or nothing if there isn't one for this kind of device.
+    pub fn icon_only(&self, style: &Style) -> String {
+        self.shown_icon(style).trim_end().to_string()
+    }
+
+    /// A block character whose height shows the battery level, from `▁` to `█`.
+    pub fn bar(&self) -> Option<char> {
+        const BARS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+        self.power
+            .map(|x| BARS[(x as usize * BARS.len() / 100).min(BARS.len() - 1)])
+    }
+
+    /// The name and percentage, with the percentage colored using tmux's `#[fg=...]` style
+    /// directives instead of pango markup.
+    pub fn tmux(&self, style: &Style) -> String {
+        let power = match (self.shown_power(style), self.level(style)) {
+            (Some(power), Some(level)) if !style.no_color => format!(
+                "#[fg={}]{}#[default]",
+                level.tmux_color(),
+                style.numbers.percent(power)
+            ),
+            (power, _) => percent(power, &style.numbers),
+        };
+        format!(
+            "{} {power}{}{}",
+            self.name,
+            self.charging_suffix(style),
+            self.age_suffix()
+        )
+    }
+
+    /// The icon, and a battery glyph and percentage colored using polybar's `%{F...}` format
+    /// tags instead of pango markup.
+    pub fn polybar(&self, style: &Style) -> String {
+        let icon = self.shown_icon(style);
+        let Some(power) = self.shown_power(style) else {
+            return format!("{icon}{UNKNOWN_POWER}{}", self.age_suffix());
+        };
+
+        // https://fontawesome.com/icons/battery-full, also in Nerd Fonts as nf-fa-battery_*
+        let ramp = match power {
+            0..=10 => '\u{f244}',
+            11..=35 => '\u{f243}',
+            36..=60 => '\u{f242}',
+            61..=85 => '\u{f241}',
+            _ => '\u{f240}',
+        };
+        let text = format!("{ramp} {}", style.numbers.percent(power));
+        let text = match self.level(style) {
+            Some(level) if !style.no_color => format!("%{{F{}}}{text}%{{F-}}", level.color()),
+            _ => text,
+        };
+        format!(
+            "{icon}{text}{}{}",
+            self.charging_suffix(style),
+            self.age_suffix()
+        )
+    }
+
+    /// How low the battery level shown is, going by `level` if it's set.
+    pub fn level(&self, style: &Style) -> Option<Level> {
+        self.shown_power(style).map(|x| {
+            self.level
+                .unwrap_or_else(|| style.thresholds_for(&self.icon).level(x))
+        })
+    }
+
+    /// The icon to show, which with `battery_icon` is for the level the device is shown at.
+    fn shown_icon<'a>(&self, style: &'a Style) -> Cow<'a, str> {
+        style.icon(&self.icon, self.shown_power(style), self.level(style))
+    }
+
+    /// The battery level to show, which with `zero_is_unknown` is never 0.
+    fn shown_power(&self, style: &Style) -> Option<u64> {
+        self.power.filter(|&x| !(style.zero_is_unknown && x == 0))
+    }
+
+    /// A lightning bolt if the battery is charging and `show_charging` is set.
+    fn charging_suffix(&self, style: &Style) -> String {
+        if !style.show_charging || self.charging != Some(true) {
+            String::new()
+        } else if style.i3 {
+            style.font.span("bolt").trim_end().to_string()
+        } else {
+            "⚡".to_string()
+        }
+    }
+
+    fn age_suffix(&self) -> String {
+        self.age
+            .map(|x| format!(" ({} ago)", format_age(x)))
+            .unwrap_or_default()
+    }
+
+    pub fn format_with(&self, template: &str, style: &Style) -> String {
+        expand_template(template, |key| match key {
+            "name" => Some(self.name.clone()),
+            "address" => Some(self.address.clone()),
+            "power" => Some(
+                self.shown_power(style)
+                    .map_or(UNKNOWN_POWER.to_string(), |x| x.to_string()),
+            ),
+            "icon" => Some(self.shown_icon(style).to_string()),
+            "icon_emoji" => Some(style.emoji(&self.icon).to_string()),
+            _ => None,
+        })
+    }
+
+    pub fn csv_row(&self) -> String {
+        format!(
+            "{},{},{}",
+            csv_field(&self.name),
+            csv_field(&self.icon.0),
+            self.power.map(|x| x.to_string()).unwrap_or_default()
+        )
+    }
+
+    /// The device as tab-separated name, icon and power fields. Tabs and newlines in the name are
+    /// replaced with spaces, so each device stays on one line.
+    pub fn tsv_row(&self) -> String {
+        format!(
+            "{}\t{}\t{}",
+            tsv_field(&self.name),
+            tsv_field(&self.icon.0),
+            self.power.map(|x| x.to_string()).unwrap_or_default()
+        )
+    }
+
+    pub fn json_fields(&self) -> [(&str, String); 4] {
+        [
+            ("name", json_string(&self.name)),
+            ("icon", json_string(&self.icon.0)),
+            (
+                "power",
+                self.power.map_or("null".to_string(), |x| x.to_string()),
+            ),
+            ("connected", self.connected.to_string()),
+        ]
+    }
+
+    pub fn json(&self, pretty: bool) -> String {
+        let fields = self.json_fields();
+        if pretty {
+            let fields = fields
+                .iter()
+                .map(|(k, v)| format!("    \"{k}\": {v}"))
+                .collect::<Vec<_>>()
+                .join(",\n");
+            format!("  {{\n{fields}\n  }}")
+        } else {
+            let fields = fields
+                .iter()
+                .map(|(k, v)| format!("\"{k}\":{v}"))
+                .collect::<Vec<_>>()
+                .join(",");
+            format!("{{{fields}}}")
+        }
+    }
+}
+
+/// How the battery levels of a pair of devices are combined by [`merge_pairs`].
+#[derive(Clone, Copy, Default)]
+pub enum MergeBy {
+    #[default]
+    Min,
+    Average,
+}
+
+impl std::str::FromStr for MergeBy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "min" => Ok(Self::Min),
+            "average" => Ok(Self::Average),
+            _ => Err("expected one of min or average"),
+        }
+    }
+}
+
+/// Combine devices that are halves of a pair, such as `Buds L` and `Buds R`, into a single
+/// device named after what they have in common.
+pub fn merge_pairs(devices: Vec<Device>, how: MergeBy) -> Vec<Device> {
+    let mut merged = Vec::<(Option<String>, Vec<Device>)>::new();
+    for device in devices {
+        let base = pair_base_name(&device.name).map(String::from);
+        let group = merged
+            .iter_mut()
+            .find(|(name, group)| base.is_some() && *name == base && group[0].icon == device.icon);
+        match group {
+            Some((_, group)) => group.push(device),
+            None => merged.push((base, vec![device])),
+        }
+    }
+
+    merged
+        .into_iter()
+        .map(|(base, mut group)| {
+            if group.len() == 1 {
+                return group.remove(0);
+            }
+
+            let levels = group.iter().filter_map(|d| d.power).collect::<Vec<_>>();
+            let power = match how {
+                MergeBy::Min => levels.iter().copied().min(),
+                MergeBy::Average => {
+                    (!levels.is_empty()).then(|| levels.iter().sum::<u64>() / levels.len() as u64)
+                }
+            };
+            group.sort_unstable_by(|a, b| a.address.cmp(&b.address));
+            let connected = group.iter().any(|d| d.connected);
+            let age = group.iter().filter_map(|d| d.age).max();
+            let seen = group
+                .iter()
+                .map(|d| d.seen)
+                .min()
+                .unwrap_or_else(SystemTime::now);
+            let rssi = group.iter().filter_map(|d| d.rssi).max();
+            let first = group.swap_remove(0);
+            Device {
+                name: base.unwrap_or(first.name),
+                icon: first.icon,
+                power,
+                connected,
+                address: first.address,
+                rssi,
+                source: first.source,
+                level: None,
+                trend: None,
+                charging: None,
+                age,
+                seen,
+            }
+        })
+        .collect()
+}
+
+/// The name shared by both halves of a pair, e.g. `Buds` for `Buds L`, `Buds (Right)` or
+/// `Buds-R`.
+fn pair_base_name(name: &str) -> Option<&str> {
+    const SEPARATORS: [char; 4] = [' ', '-', '_', '('];
+
+    let name = name.trim_end().strip_suffix(')').unwrap_or(name.trim_end());
+    let lower = name.to_ascii_lowercase();
+    ["left", "right", "l", "r"].iter().find_map(|suffix| {
+        let base = name.get(..name.len().checked_sub(suffix.len())?)?;
+        if !lower.ends_with(suffix) || !base.ends_with(SEPARATORS) {
+            return None;
+        }
+        Some(base.trim_end_matches(SEPARATORS)).filter(|x| !x.is_empty())
+    })
+}
+
+/// Format a duration in its largest whole unit, e.g. `3m`.
+pub fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
+}
+
+/// Format a time as an ISO 8601 date and time in UTC, to the second, e.g.
+/// `2024-05-01T12:34:56Z`.
+pub fn format_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// Pad `s` with spaces on the right to take up at least `width` columns of a terminal.
+pub fn pad(s: &str, width: usize) -> String {
+    let padding = width.saturating_sub(display_width(s));
+    format!("{s}{}", " ".repeat(padding))
+}
+
+/// How many columns of a terminal `s` takes up, counting wide characters (like emoji and CJK)
+/// as two and combining characters as none.
+///
+/// This covers the common ranges rather than all of Unicode's East Asian Width property.
+pub fn display_width(s: &str) -> usize {
+    s.chars().map(char_width).sum()
+}
+
+fn char_width(c: char) -> usize {
+    match c as u32 {
+        0x0300..=0x036F | 0x200B..=0x200F | 0x20D0..=0x20FF | 0xFE00..=0xFE0F => 0,
+        0x1F3FB..=0x1F3FF | 0xE0020..=0xE007F => 0,
+        0x1100..=0x115F
+        | 0x231A..=0x231B
+        | 0x23E9..=0x23EC
+        | 0x23F0..=0x23F3
+        | 0x25FD..=0x25FE
+        | 0x2614..=0x2615
+        | 0x2E80..=0x303E
+        | 0x3041..=0xA4CF
+        | 0xAC00..=0xD7A3
+        | 0xF900..=0xFAFF
+        | 0xFE30..=0xFE4F
+        | 0xFF00..=0xFF60
+        | 0xFFE0..=0xFFE6
+        | 0x1F300..=0x1F64F
+        | 0x1F680..=0x1F6FF
+        | 0x1F900..=0x1F9FF
+        | 0x1FA70..=0x1FAFF
+        | 0x20000..=0x3FFFD => 2,
+        x if x < 0x20 || (0x7F..0xA0).contains(&x) => 0,
+        _ => 1,
+    }
+}
+
+/// Shown in place of the battery level when it isn't known.
+pub const UNKNOWN_POWER: &str = "-";
+
+fn percent(power: Option<u64>, numbers: &NumberFormat) -> String {
+    power.map_or(UNKNOWN_POWER.to_string(), |x| numbers.percent(x))
+}
+
+// https://www.rfc-editor.org/rfc/rfc4180#section-2
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+fn tsv_field(s: &str) -> String {
+    s.replace(['\t', '\n', '\r'], " ")
+}
+
+/// The object expected by Waybar's `custom` module when `return-type` is `json`.
+///
+/// See `waybar-custom(5)`.
+pub struct Waybar {
+    text: String,
+    tooltip: String,
+    percentage: Option<u64>,
+    class: Option<&'static str>,
+}
+
+impl Waybar {
+    pub fn new(devices: &[Device], style: &Style) -> Self {
+        let text = devices
+            .iter()
+            .map(|d| d.narrow(style))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let tooltip = devices
+            .iter()
+            .map(|d| d.long(style))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let percentage = devices.iter().filter_map(|d| d.shown_power(style)).min();
+        let class = devices
+            .iter()
+            .filter_map(|d| d.level(style))
+            .max()
+            .map(Level::class);
+
+        Self {
+            text,
+            tooltip,
+            percentage,
+            class,
+        }
+    }
+
+    pub fn json(&self) -> String {
+        let mut fields = vec![
+            format!("\"text\":{}", json_string(&self.text)),
+            format!("\"tooltip\":{}", json_string(&self.tooltip)),
+        ];
+        if let Some(percentage) = self.percentage {
+            fields.push(format!("\"percentage\":{percentage}"));
+        }
+        if let Some(class) = self.class {
+            fields.push(format!("\"class\":{}", json_string(class)));
+        }
+        format!("{{{}}}", fields.join(","))
+    }
+
+    /// The text and tooltip as two lines, for bars other than Waybar that take them that way.
+    pub fn lines(&self) -> String {
+        // The tooltip has to fit on its own line.
+        format!("{}\n{}", self.text, self.tooltip.replace('\n', ", "))
+    }
+}
+
+/// Replace each `{key}` in `template` with the value returned by `lookup`.
+///
+/// Placeholders that `lookup` doesn't know about are left as they are, and `{{` and `}}` are
+/// unescaped to single braces.
+fn expand_template(template: &str, mut lookup: impl FnMut(&str) -> Option<String>) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(i) = rest.find(['{', '}']) {
+        out.push_str(&rest[..i]);
+        rest = &rest[i..];
+
+        if let Some(tail) = rest.strip_prefix("{{").or_else(|| rest.strip_prefix("}}")) {
+            out.push_str(&rest[..1]);
+            rest = tail;
+            continue;
+        }
+
+        match rest[1..].find('}').map(|end| &rest[1..=end]) {
+            Some(key) if rest.starts_with('{') && !key.contains('{') => {
+                match lookup(key) {
+                    Some(value) => out.push_str(&value),
+                    None => {
+                        out.push('{');
+                        out.push_str(key);
+                        out.push('}');
+                    }
+                }
+                rest = &rest[key.len() + 2..];
+            }
+            _ => {
+                out.push_str(&rest[..1]);
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+pub const PLACEHOLDERS: &[&str] = &["name", "address", "power", "icon", "icon_emoji"];
+
+pub fn unknown_placeholders(template: &str) -> Vec<String> {
+    let mut unknown = vec![];
+    expand_template(template, |key| {
+        if !PLACEHOLDERS.contains(&key) {
+            unknown.push(key.to_string());
+        }
+        None
+    });
+    unknown
+}
+
+pub fn json_array(devices: &[Device], pretty: bool) -> String {
+    if devices.is_empty() {
+        return "[]".to_string();
+    }
+
+    let items = devices.iter().map(|d| d.json(pretty)).collect::<Vec<_>>();
+    if pretty {
+        format!("[\n{}\n]", items.join(",\n"))
+    } else {
+        format!("[{}]", items.join(","))
+    }
+}
+
+/// The version of the object written by [`json_envelope`], bumped whenever its fields change in a
+/// way that could break consumers.
+pub const JSON_VERSION: u32 = 1;
+
+/// The devices as a JSON array, wrapped in an object along with [`JSON_VERSION`].
+pub fn json_envelope(devices: &[Device], pretty: bool) -> String {
+    let devices = json_array(devices, pretty);
+    if pretty {
+        format!(
+            "{{\n  \"version\": {JSON_VERSION},\n  \"devices\": {}\n}}",
+            devices.replace('\n', "\n  ")
+        )
+    } else {
+        format!("{{\"version\":{JSON_VERSION},\"devices\":{devices}}}")
+    }
+}
+
+/// One line per kind of device, with how many there are and the lowest battery level among them,
+/// e.g. `🎧 2 devices, min 40%`.
+pub fn summary(devices: &[Device], style: &Style) -> String {
+    let mut groups = BTreeMap::<&Icon, (usize, Option<u64>)>::new();
+    for device in devices {
+        let (count, min) = groups.entry(&device.icon).or_default();
+        *count += 1;
+        *min = match (*min, device.power) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+    }
+
+    groups
+        .into_iter()
+        .map(|(icon, (count, min))| {
+            format!(
+                "{}{count} {}, min {}",
+                style.icon(icon, min, min.map(|x| style.thresholds_for(icon).level(x))),
+                if count == 1 { "device" } else { "devices" },
+                style.power_at(min, min.map(|x| style.thresholds_for(icon).level(x)))
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// The battery levels of the connected devices in the Prometheus text format, e.g. for
+/// node_exporter's textfile collector.
+pub fn prometheus_metrics(devices: &[Device]) -> String {
+    let mut out = String::from(
+        "# HELP bluetooth_battery_percent Battery level of a connected bluetooth device.\n\
+         # TYPE bluetooth_battery_percent gauge",
+    );
+    for device in devices.iter().filter(|d| d.connected) {
+        let Some(power) = device.power else {
+            continue;
+        };
+        out.push_str(&format!(
+            "\nbluetooth_battery_percent{{name=\"{}\",address=\"{}\",icon=\"{}\"}} {power}",
+            prometheus_label(&device.name),
+            prometheus_label(&device.address),
+            prometheus_label(&device.icon.0)
+        ));
+    }
+    out
+}
+
+// https://prometheus.io/docs/instrumenting/exposition_formats/#text-format-details
+fn prometheus_label(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// https://www.rfc-editor.org/rfc/rfc8259#section-7
+pub fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Icon(pub String);
+
+impl std::str::FromStr for Icon {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.to_string()))
+    }
+}
+
+impl Icon {
+    /// The freedesktop status icon for a battery level, e.g. `battery-low`.
+    pub fn battery(power: Option<u64>, thresholds: &Thresholds) -> Self {
+        Self::battery_at(power, power.map(|x| thresholds.level(x)))
+    }
+
+    /// Like [`Icon::battery`], but at `level`, e.g. as held there by hysteresis, rather than the
+    /// one from the thresholds.
+    pub fn battery_at(power: Option<u64>, level: Option<Level>) -> Self {
+        let name = match (power, level) {
+            (None, _) => "battery-missing",
+            (Some(0), _) => "battery-empty",
+            (Some(_), Some(Level::Critical)) => "battery-caution",
+            (Some(_), Some(Level::Warning)) => "battery-low",
+            (Some(power), _) if power >= 90 => "battery-full",
+            (Some(_), _) => "battery-good",
+        };
+        Self(name.to_string())
+    }
+
+    pub fn emoji(&self) -> Option<&'static str> {
+        self.builtin().and_then(|x| x.emoji)
+    }
+
+    /// The name of the Material Symbols glyph, e.g. `headphones`.
+    pub fn material_symbols(&self) -> Option<&'static str> {
+        self.builtin().and_then(|x| x.material_symbols)
+    }
+
+    fn builtin(&self) -> Option<&'static BuiltinIcon> {
+        BUILTIN_ICONS.iter().find(|x| x.name == self.0)
+    }
+
+    // https://www.nerdfonts.com/cheat-sheet
+    pub fn nerd_font(&self) -> Option<&'static str> {
+        // https://specifications.freedesktop.org/icon-naming-spec/latest/#devices
+        match self.0.as_str() {
+            "audio-headset" => Some("\u{f02cb} "),     // nf-md-headphones
+            "phone" | "pda" => Some("\u{f011c} "),     // nf-md-cellphone
+            "input-keyboard" => Some("\u{f030c} "),    // nf-md-keyboard
+            "input-mouse" => Some("\u{f037d} "),       // nf-md-mouse
+            "input-gaming" => Some("\u{f0297} "),      // nf-md-gamepad_variant
+            "input-tablet" => Some("\u{f04f6} "),      // nf-md-tablet
+            "multimedia-player" => Some("\u{f04c3} "), // nf-md-speaker
+            "printer" => Some("\u{f042a} "),           // nf-md-printer
+            "scanner" => Some("\u{f06ab} "),           // nf-md-scanner
+            "battery-full" => Some("\u{f0079} "),      // nf-md-battery
+            "battery-good" => Some("\u{f0080} "),      // nf-md-battery_70
+            "battery-low" => Some("\u{f007c} "),       // nf-md-battery_30
+            "battery-caution" => Some("\u{f0083} "),   // nf-md-battery_alert
+            "battery-empty" => Some("\u{f008e} "),     // nf-md-battery_outline
+            "battery-missing" => Some("\u{f0091} "),   // nf-md-battery_unknown
+            _ => None,
+        }
+    }
+}
+
+/// The glyphs built in for one freedesktop icon name.
+pub struct BuiltinIcon {
+    pub name: &'static str,
+    /// The emoji, followed by enough spaces to take up three columns.
+    pub emoji: Option<&'static str>,
+    /// The name of the Material Symbols glyph, e.g. `headphones`.
+    pub material_symbols: Option<&'static str>,
+}
+
+/// Every icon with a built-in emoji or Material Symbols glyph.
+// https://specifications.freedesktop.org/icon-naming-spec/latest/#devices
+pub static BUILTIN_ICONS: &[BuiltinIcon] = &[
+    builtin("audio-headset", Some("🎧 "), Some("headphones")),
+    builtin("phone", Some("📱 "), Some("smartphone")),
+    builtin("pda", Some("📱 "), Some("smartphone")),
+    builtin("input-keyboard", Some("⌨️ "), Some("keyboard")),
+    builtin("input-mouse", Some("🖱️ "), Some("mouse")),
+    builtin("input-gaming", Some("🎮 "), Some("sports_esports")),
+    builtin("input-tablet", Some("🖍️  "), Some("tablet_android")),
+    builtin("multimedia-player", Some("📻 "), Some("media_bluetooth_on")),
+    builtin("printer", Some("🖨️  "), Some("print")),
+    builtin("scanner", Some("🖨️  "), Some("scanner")),
+    builtin("battery-full", Some("🔋 "), Some("battery_full")),
+    builtin("battery-good", Some("🔋 "), Some("battery_5_bar")),
+    builtin("battery-low", Some("🪫 "), Some("battery_2_bar")),
+    builtin("battery-caution", Some("🪫 "), Some("battery_alert")),
+    builtin("battery-empty", Some("🪫 "), Some("battery_0_bar")),
+    builtin("battery-missing", None, Some("battery_unknown")),
+];
+
+const fn builtin(
+    name: &'static str,
+    emoji: Option<&'static str>,
+    material_symbols: Option<&'static str>,
+) -> BuiltinIcon {
+    BuiltinIcon {
+        name,
+        emoji,
+        material_symbols,
+    }
+}
+
+/// How devices are rendered.
+#[derive(Default)]
+pub struct Style {
+    /// Use pango markup and Material Symbols instead of emoji.
+    pub i3: bool,
+    /// Use Nerd Font glyphs for icons, regardless of `i3`.
+    pub nerd: bool,
+    /// Color the percentage according to `thresholds`. Only used with pango markup.
+    pub color: bool,
+    /// Never add colors, even with `color` or in the tmux and polybar formats. See
+    /// <https://no-color.org>.
+    pub no_color: bool,
+    /// Show a battery level of 0 as unknown, for devices that report it before they know better.
+    pub zero_is_unknown: bool,
+    /// Never show icons, not even `default_icon`.
+    pub no_icon: bool,
+    /// Used as is (apart from a trailing space) for devices without an icon, instead of nothing.
+    pub default_icon: Option<String>,
+    /// In the long and short formats, show each device's address after its name.
+    pub show_address: bool,
+    /// In the long format, show each device's signal strength, if it's known.
+    pub show_rssi: bool,
+    /// In the long format, show where each device's battery level came from, if it's known.
+    pub show_source: bool,
+    /// After the battery level, show whether the device is charging, if it's known.
+    pub show_charging: bool,
+    /// In the narrow format, don't put a space between the icon and the battery level.
+    pub compact: bool,
+    /// Show an icon for the battery level instead of the kind of device.
+    pub battery_icon: bool,
+    /// In the long format, show how old the battery level is even if it was just read.
+    pub show_age: bool,
+    /// In the narrow format, show the battery level as a bar instead of a percentage.
+    pub bar: bool,
+    /// With `bar`, show the percentage after the bar as well.
+    pub show_percent: bool,
+    pub thresholds: Thresholds,
+    pub font: SymbolFont,
+    pub numbers: NumberFormat,
+    /// Icons from the configuration file, which take precedence over the built-in ones.
+    custom_icons: HashMap<String, CustomIcon>,
+    /// Glyphs from an icon map file, used as is in every format instead of any other icon.
+    icon_map: HashMap<String, String>,
+    /// Thresholds from the configuration file, keyed by icon name, which take precedence over
+    /// `thresholds`.
+    icon_thresholds: HashMap<String, config::IconThresholds>,
+}
+
+/// How numbers are written, following the conventions of a locale.
+pub struct NumberFormat {
+    /// Put between a number and the percent sign, e.g. a no-break space in German.
+    pub percent_space: &'static str,
+    pub decimal_separator: char,
+}
+
+impl Default for NumberFormat {
+    fn default() -> Self {
+        Self {
+            percent_space: "",
+            decimal_separator: '.',
+        }
+    }
+}
+
+impl NumberFormat {
+    /// The conventions of a POSIX locale name like `fr_FR.UTF-8`, going by its language alone.
+    /// Languages that aren't known get the default, `80%` and `0.5`.
+    pub fn for_locale(locale: &str) -> Self {
+        let language = locale
+            .split(['_', '-', '.', '@'])
+            .next()
+            .unwrap_or_default();
+        // From the percent and decimal formats in CLDR.
+        let percent_space = match language {
+            "fr" => "\u{202f}",
+            "cs" | "da" | "de" | "es" | "fi" | "nb" | "nn" | "no" | "ru" | "sk" | "sv" => "\u{a0}",
+            _ => "",
+        };
+        let decimal_separator = match language {
+            "cs" | "da" | "de" | "es" | "fi" | "fr" | "it" | "nb" | "nl" | "nn" | "no" | "pl"
+            | "pt" | "ru" | "sk" | "sv" | "tr" | "uk" => ',',
+            _ => '.',
+        };
+
+        Self {
+            percent_space,
+            decimal_separator,
+        }
+    }
+
+    pub fn percent(&self, power: u64) -> String {
+        format!("{power}{}%", self.percent_space)
+    }
+
+    /// Write `value` with `precision` digits after the decimal separator.
+    pub fn decimal(&self, value: f64, precision: usize) -> String {
+        format!("{value:.precision$}").replace('.', &self.decimal_separator.to_string())
+    }
+}
+
+/// A user-defined icon, in the same form as the built-in ones.
+struct CustomIcon {
+    emoji: Option<String>,
+    material_symbols: Option<String>,
+}
+
+/// The variable font settings used to render Material Symbols glyphs.
+///
+/// See <https://fonts.google.com/knowledge/glossary/variable_fonts> for what the axes mean.
+pub struct SymbolFont {
+    /// The optical size, which should match the font size in points for the best results.
+    pub opsz: u32,
+    /// How filled in the glyph is, from 0 to 1.
+    pub fill: f32,
+    /// The grade, i.e. how thick the strokes are, from -25 to 200.
+    pub grad: i32,
+    /// How far to raise the glyph to line it up with the text, e.g. `-3pt`.
+    pub rise: String,
+}
+
+impl Default for SymbolFont {
+    fn default() -> Self {
+        Self {
+            opsz: 20,
+            fill: 1.0,
+            grad: -25,
+            rise: "-3pt".to_string(),
+        }
+    }
+}
+
+impl SymbolFont {
+    // https://docs.gtk.org/Pango/pango_markup.html#the-span-attributes
+    pub fn span(&self, glyph: &str) -> String {
+        format!(
+            "<span font_desc='Material Symbols Outlined @opsz={},FILL={},GRAD={}' rise='{}'>{glyph}</span> ",
+            self.opsz, self.fill, self.grad, self.rise
+        )
+    }
+}
+
+impl Style {
+    /// Use `icons` from the configuration file, replacing any from before.
+    pub fn set_icons(&mut self, icons: HashMap<String, config::IconConfig>) {
+        self.custom_icons.clear();
+        for (name, icon) in icons {
+            let custom = CustomIcon {
+                emoji: icon.emoji.map(|x| format!("{x} ")),
+                material_symbols: icon.material_symbols,
+            };
+            self.custom_icons.insert(name, custom);
+        }
+    }
+
+    /// Use the glyphs from an icon map file, e.g. for a particular icon theme.
+    pub fn set_icon_map(&mut self, glyphs: HashMap<String, String>) {
+        self.icon_map = glyphs
+            .into_iter()
+            .map(|(name, glyph)| (name, format!("{glyph} ")))
+            .collect();
+    }
+
+    /// The names of the icons in the icon map, sorted.
+    pub fn mapped_icons(&self) -> Vec<&str> {
+        let mut names = self.icon_map.keys().map(String::as_str).collect::<Vec<_>>();
+        names.sort_unstable();
+        names
+    }
+
+    /// The glyph from the icon map, followed by a space.
+    fn mapped(&self, icon: &Icon) -> Option<&str> {
+        self.icon_map.get(&icon.0).map(String::as_str)
+    }
+
+    /// Use thresholds from the configuration file for some kinds of devices.
+    pub fn set_icon_thresholds(&mut self, thresholds: HashMap<String, config::IconThresholds>) {
+        self.icon_thresholds = thresholds;
+    }
+
+    /// The thresholds for devices with this icon, from the configuration file if it has them.
+    pub fn thresholds_for(&self, icon: &Icon) -> Thresholds {
+        match self.icon_thresholds.get(&icon.0) {
+            Some(x) => Thresholds {
+                warn: x.warn.unwrap_or(self.thresholds.warn),
+                crit: x.crit.unwrap_or(self.thresholds.crit),
+            },
+            None => self.thresholds,
+        }
+    }
+
+    pub fn power(&self, power: Option<u64>) -> String {
+        self.power_at(power, power.map(|x| self.thresholds.level(x)))
+    }
+
+    /// Like [`Style::power`], but colored as `level` rather than by the thresholds.
+    fn power_at(&self, power: Option<u64>, level: Option<Level>) -> String {
+        match (power, level) {
+            (Some(power), Some(level)) if self.i3 && self.color && !self.no_color => format!(
+                "<span color='{}'>{}</span>",
+                level.color(),
+                self.numbers.percent(power)
+            ),
+            (power, _) => percent(power, &self.numbers),
+        }
+    }
+
+    /// The icon for a device, or with `battery_icon`, for its battery `power` at `level`.
+    pub fn icon<'a>(
+        &'a self,
+        icon: &Icon,
+        power: Option<u64>,
+        level: Option<Level>,
+    ) -> Cow<'a, str> {
+        if self.no_icon {
+            return "".into();
+        }
+
+        let battery;
+        let icon = if self.battery_icon {
+            battery = Icon::battery_at(power, level);
+            &battery
+        } else {
+            icon
+        };
+
+        let icon: Cow<str> = if self.nerd {
+            self.mapped(icon)
+                .or_else(|| icon.nerd_font())
+                .unwrap_or_default()
+                .into()
+        } else if self.i3 {
+            self.material_symbols(icon).into()
+        } else {
+            self.emoji(icon).into()
+        };
+
+        match &self.default_icon {
+            Some(default) if icon.is_empty() => format!("{default} ").into(),
+            _ => icon,
+        }
+    }
+
+    /// Whether there's an emoji or Material Symbols glyph for the icon, built in, from the
+    /// configuration file or from the icon map.
+    pub fn knows_icon(&self, icon: &Icon) -> bool {
+        let custom = self.custom_icons.get(&icon.0);
+        self.mapped(icon).is_some()
+            || custom.is_some_and(|x| x.emoji.is_some() || x.material_symbols.is_some())
+            || icon.emoji().is_some()
+            || icon.material_symbols().is_some()
+    }
+
+    /// The emoji for the icon, or the glyph from the icon map if it has one.
+    pub fn emoji<'a>(&'a self, icon: &Icon) -> &'a str {
+        self.mapped(icon)
+            .or_else(|| self.custom_icons.get(&icon.0)?.emoji.as_deref())
+            .or_else(|| icon.emoji())
+            .unwrap_or_default()
+    }
+
+    /// The Material Symbols glyph for the icon, in pango markup, or the glyph from the icon map
+    /// as is if it has one.
+    pub fn material_symbols(&self, icon: &Icon) -> String {
+        if let Some(glyph) = self.mapped(icon) {
+            return glyph.to_string();
+        }
+        self.material_symbols_name(icon)
+            .map(|x| self.font.span(x))
+            .unwrap_or_default()
+    }
+
+    /// The name of the Material Symbols glyph for the icon, e.g. `headphones`, or the glyph from
+    /// the icon map if it has one.
+    pub fn material_symbols_name<'a>(&'a self, icon: &Icon) -> Option<&'a str> {
+        self.mapped(icon)
+            .map(str::trim_end)
+            .or_else(|| self.custom_icons.get(&icon.0)?.material_symbols.as_deref())
+            .or_else(|| icon.material_symbols())
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub enum DeviceFormat {
+    Long,
+    Short,
+    #[default]
+    Narrow,
+    IconOnly,
+}
+
+impl std::str::FromStr for DeviceFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "long" => Ok(Self::Long),
+            "short" => Ok(Self::Short),
+            "narrow" => Ok(Self::Narrow),
+            "icon-only" => Ok(Self::IconOnly),
+            _ => Err("expected one of long, short, narrow or icon-only"),
+        }
+    }
+}
+
+/// Battery levels at or below which a device is considered low.
+#[derive(Debug, Clone, Copy)]
+pub struct Thresholds {
+    pub warn: u64,
+    pub crit: u64,
+}
+
+impl Default for Thresholds {
+    fn default() -> Self {
+        Self { warn: 50, crit: 20 }
+    }
+}
+
+impl Thresholds {
+    pub fn level(&self, power: u64) -> Level {
+        if power <= self.crit {
+            Level::Critical
+        } else if power <= self.warn {
+            Level::Warning
+        } else {
+            Level::Normal
+        }
+    }
+
+    /// The level for `power`, except that a device stays at its `previous` level until it's
+    /// more than `margin` above that level's threshold, so that it doesn't flicker between
+    /// levels.
+    pub fn level_after(&self, power: u64, previous: Option<Level>, margin: u64) -> Level {
+        let level = self.level(power);
+        [(Level::Critical, self.crit), (Level::Warning, self.warn)]
+            .into_iter()
+            .find(|&(held, threshold)| {
+                previous.is_some_and(|x| x >= held) && held > level && power <= threshold + margin
+            })
+            .map_or(level, |(held, _)| held)
+    }
+}
+
+/// Which way a device's battery level went the last time it changed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Trend {
+    Rising,
+    Falling,
+    Steady,
+}
+
+impl Trend {
+    pub fn arrow(self) -> &'static str {
+        match self {
+            Self::Rising => "↑",
+            Self::Falling => "↓",
+            Self::Steady => "→",
+        }
+    }
+}
+
+/// How low a battery level is, from best to worst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Normal,
+    Warning,
+    Critical,
+}
+
+impl Level {
+    pub fn class(self) -> &'static str {
+        match self {
+            Self::Normal => "normal",
+            Self::Warning => "warning",
+            Self::Critical => "critical",
+        }
+    }
+
+    pub fn color(self) -> &'static str {
+        match self {
+            Self::Normal => "#00ff00",
+            Self::Warning => "#ffff00",
+            Self::Critical => "#ff0000",
+        }
+    }
+
+    /// The closest colors from tmux's 256-color palette.
+    pub fn tmux_color(self) -> &'static str {
+        match self {
+            Self::Normal => "colour46",
+            Self::Warning => "colour226",
+            Self::Critical => "colour196",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use dbus::arg::Variant;
+
+    use super::*;
+
+    /// A fake BlueZ, serving properties from a fixed set of objects.
+    #[derive(Default)]
+    struct MockSource {
+        objects: ManagedObjects,
+    }
+
+    impl MockSource {
+        fn with_adapter(mut self, adapter: &str) -> Self {
+            self.objects.insert(
+                Path::from(format!("/org/bluez/{adapter}")),
+                HashMap::from([("org.bluez.Adapter1".to_string(), PropMap::new())]),
+            );
+            self
+        }
+
+        fn with_device(
+            mut self,
+            address: &str,
+            name: &str,
+            icon: &str,
+            connected: bool,
+            power: Option<u8>,
+        ) -> Self {
+            let mut interfaces = HashMap::from([(
+                "org.bluez.Device1".to_string(),
+                PropMap::from([
+                    ("Address".to_string(), variant(address.to_string())),
+                    ("Name".to_string(), variant(name.to_string())),
+                    ("Icon".to_string(), variant(icon.to_string())),
+                    ("Connected".to_string(), variant(connected)),
+                ]),
+            )]);
+            if let Some(power) = power {
+                interfaces.insert(
+                    "org.bluez.Battery1".to_string(),
+                    PropMap::from([("Percentage".to_string(), variant(power))]),
+                );
+            }
+            self.objects
+                .insert(Path::from(device_path("hci0", address)), interfaces);
+            self
+        }
+    }
+
+    impl BatterySource for MockSource {
+        fn managed_objects(&self) -> Result<ManagedObjects, dbus::Error> {
+            Ok(self
+                .objects
+                .iter()
+                .map(|(path, interfaces)| {
+                    let interfaces = interfaces
+                        .iter()
+                        .map(|(name, props)| {
+                            let props = props
+                                .iter()
+                                .map(|(k, v)| (k.clone(), Variant(v.0.box_clone())))
+                                .collect();
+                            (name.clone(), props)
+                        })
+                        .collect();
+                    (path.clone(), interfaces)
+                })
+                .collect())
+        }
+
+        fn get_property(
+            &self,
+            path: &str,
+            interface: &str,
+            name: &str,
+        ) -> Result<Box<dyn RefArg>, dbus::Error> {
+            self.objects
+                .get(&Path::from(path))
+                .and_then(|x| x.get(interface))
+                .and_then(|x| x.get(name))
+                .map(|x| x.0.box_clone())
+                .ok_or_else(|| {
+                    dbus::Error::new_custom(
+                        "org.freedesktop.DBus.Error.InvalidArgs",
+                        &format!("no {interface}.{name} on {path}"),
+                    )
+                })
+        }
+
+        fn get_all(&self, path: &str, interface: &str) -> Result<PropMap, dbus::Error> {
+            self.objects
+                .get(&Path::from(path))
+                .and_then(|x| x.get(interface))
+                .map(|x| {
+                    x.iter()
+                        .map(|(k, v)| (k.clone(), Variant(v.0.box_clone())))
+                        .collect()
+                })
+                .ok_or_else(|| {
+                    dbus::Error::new_custom(
+                        "org.freedesktop.DBus.Error.InvalidArgs",
+                        &format!("no {interface} on {path}"),
+                    )
+                })
+        }
+
+        fn child_paths(&self, path: &str) -> Result<Vec<String>, dbus::Error> {
+            let prefix = format!("{path}/");
+            let mut children = self
+                .objects
+                .keys()
+                .filter_map(|x| x.strip_prefix(&prefix))
+                .map(|x| format!("{prefix}{}", x.split('/').next().unwrap_or(x)))
+                .collect::<Vec<_>>();
+            children.sort_unstable();
+            children.dedup();
+            Ok(children)
+        }
+    }
+
+    fn variant(value: impl RefArg + 'static) -> Variant<Box<dyn RefArg>> {
+        Variant(Box::new(value))
+    }
+
+    fn device(name: &str, icon: &str, power: Option<u64>) -> Device {
+        Device {
+            address: "AA:BB:CC:DD:EE:FF".to_string(),
+            ..Device::new(name, icon, power)
+        }
+    }
+
+    fn source() -> MockSource {
+        MockSource::default()
+            .with_adapter("hci0")
+            .with_device(
+                "AA:BB:CC:DD:EE:01",
+                "Headset",
+                "audio-headset",
+                true,
+                Some(80),
+            )
+            .with_device("AA:BB:CC:DD:EE:02", "Mouse", "input-mouse", false, Some(40))
+            .with_device(
+                "AA:BB:CC:DD:EE:03",
+                "Keyboard",
+                "input-keyboard",
+                true,
+                None,
+            )
+    }
+
+    #[test]
+    fn fetch_all_devices_skips_other_objects() {
+        let mut devices = fetch_all_devices(&source()).unwrap();
+        devices.sort_unstable();
+
+        assert_eq!(
+            devices
+                .iter()
+                .map(|d| (d.name.as_str(), d.connected, d.power))
+                .collect::<Vec<_>>(),
+            [
+                ("Headset", true, Some(80)),
+                ("Keyboard", true, None),
+                ("Mouse", false, Some(40)),
+            ]
+        );
+    }
+
+    #[test]
+    fn fetches_devices_one_by_one() {
+        let mut source = source();
+        source.objects.insert(
+            Path::from("/org/bluez/hci0/dev_AA_BB_CC_DD_EE_04"),
+            HashMap::from([("org.bluez.Battery1".to_string(), PropMap::new())]),
+        );
+
+        let (mut devices, failures) = fetch_devices_one_by_one(&source, None).unwrap();
+        devices.sort_unstable();
+        assert_eq!(
+            devices.iter().map(|d| d.name.as_str()).collect::<Vec<_>>(),
+            ["Headset", "Keyboard", "Mouse"]
+        );
+        assert_eq!(
+            failures.iter().map(|(x, _)| x.as_str()).collect::<Vec<_>>(),
+            ["/org/bluez/hci0/dev_AA_BB_CC_DD_EE_04"]
+        );
+        assert!(
+            fetch_devices_one_by_one(&source, Some("hci1"))
+                .unwrap()
+                .0
+                .is_empty()
+        );
+
+        assert_eq!(
+            child_nodes(r#"<node name="/org/bluez"><node name="hci0"/><node name="hci1"/></node>"#)
+                .collect::<Vec<_>>(),
+            ["hci0", "hci1"]
+        );
+    }
+
+    #[test]
+    fn falls_back_to_alias_without_name() {
+        let mut source = source();
+        let path = Path::from(device_path("hci0", "AA:BB:CC:DD:EE:01"));
+        let device = source
+            .objects
+            .get_mut(&path)
+            .and_then(|x| x.get_mut("org.bluez.Device1"))
+            .unwrap();
+        device.remove("Name");
+        assert!(device_from_interfaces(&source.objects[&path]).is_none());
+
+        let device = source
+            .objects
+            .get_mut(&path)
+            .and_then(|x| x.get_mut("org.bluez.Device1"))
+            .unwrap();
+        device.insert("Alias".to_string(), variant("My Headset".to_string()));
+        assert_eq!(
+            device_from_interfaces(&source.objects[&path]).map(|d| d.name),
+            Some("My Headset".to_string())
+        );
+    }
+
+    #[test]
+    fn filters_devices_by_adapter() {
+        let mut objects = source().managed_objects().unwrap();
+        let path = Path::from(device_path("hci0", "AA:BB:CC:DD:EE:01"));
+        let headset = objects.remove(&path).unwrap();
+        objects.insert(
+            Path::from(device_path("hci1", "AA:BB:CC:DD:EE:01")),
+            headset,
+        );
+
+        let devices = devices_from_objects(&objects, Some("hci1"));
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].name, "Headset");
+        assert_eq!(devices_from_objects(&objects, None).len(), 3);
+        assert!(!is_on_adapter("/org/bluez/hci10/dev_AA", "hci1"));
+    }
+
+    #[test]
+    fn fetch_device_reads_battery_of_connected_device() {
+        let device = fetch_device(&source(), &device_path("hci0", "aa:bb:cc:dd:ee:01")).unwrap();
+        assert_eq!(device.name, "Headset");
+        assert_eq!(device.icon, Icon("audio-headset".to_string()));
+        assert_eq!(device.power, Some(80));
+        assert_eq!(device.address, "AA:BB:CC:DD:EE:01");
+    }
+
+    #[test]
+    fn fetch_device_ignores_battery_of_disconnected_device() {
+        let device = fetch_device(&source(), &device_path("hci0", "AA:BB:CC:DD:EE:02")).unwrap();
+        assert!(!device.connected);
+        assert_eq!(device.power, None);
+    }
+
+    #[test]
+    fn fetch_device_fails_without_battery() {
+        assert!(fetch_device(&source(), &device_path("hci0", "AA:BB:CC:DD:EE:03")).is_err());
+        assert!(fetch_device(&source(), &device_path("hci0", "AA:BB:CC:DD:EE:04")).is_err());
+    }
+
+    #[test]
+    fn converts_quirky_percentages() {
+        assert_eq!(percentage(&80u8), Some(80));
+        assert_eq!(percentage(&-5i32), Some(0));
+        assert_eq!(percentage(&63.6f64), Some(64));
+        assert_eq!(percentage(&120i16), Some(120));
+        assert_eq!(percentage(&f64::NAN), None);
+        assert_eq!(percentage(&"80".to_string()), None);
+    }
+
+    #[test]
+    fn clamps_only_when_asked() {
+        let mut from_bluez = device("Buds", "audio-headset", percentage(&120u8));
+        let mut from_upower = device("Mouse", "input-mouse", upower_percentage(101.4));
+        assert_eq!(upower_percentage(-3.0), Some(0));
+
+        from_bluez.round(5);
+        assert_eq!(from_bluez.power, Some(120));
+        assert_eq!(from_upower.power, Some(101));
+
+        from_bluez.clamp_power();
+        from_upower.clamp_power();
+        assert_eq!(from_bluez.power, Some(100));
+        assert_eq!(from_upower.power, Some(100));
+
+        let mut full = device("Phone", "phone", Some(100));
+        full.round(40);
+        assert_eq!(full.power, Some(100));
+    }
+
+    #[test]
+    fn finds_upower_addresses() {
+        assert_eq!(
+            find_address("/org/bluez/hci0/dev_aa_BB_CC_DD_EE_01").as_deref(),
+            Some("AA:BB:CC:DD:EE:01")
+        );
+        assert_eq!(
+            find_address("hid-aa:bb:cc:dd:ee:02-battery").as_deref(),
+            Some("AA:BB:CC:DD:EE:02")
+        );
+        assert_eq!(find_address("BAT0"), None);
+    }
+
+    #[test]
+    fn resolves_partial_addresses() {
+        let objects = source().managed_objects().unwrap();
+        assert_eq!(
+            resolve_address(&objects, "hci0", "dd:ee:02").unwrap(),
+            "AA:BB:CC:DD:EE:02"
+        );
+        assert_eq!(
+            resolve_address(&objects, "hci0", "EE:04")
+                .unwrap_err()
+                .to_string(),
+            "no device's address ends with EE:04"
+        );
+        assert!(resolve_address(&objects, "hci1", "EE:03").is_err());
+
+        let objects = source()
+            .with_device("11:22:33:44:55:02", "Pad", "input-gaming", true, Some(90))
+            .managed_objects()
+            .unwrap();
+        assert_eq!(
+            resolve_address(&objects, "hci0", "02")
+                .unwrap_err()
+                .to_string(),
+            "more than one device's address ends with 02: 11:22:33:44:55:02, AA:BB:CC:DD:EE:02"
+        );
+    }
+
+    #[test]
+    fn check_adapter_lists_available_adapters() {
+        let objects = source().with_adapter("hci1").managed_objects().unwrap();
+        assert!(check_adapter(&objects, "hci1").is_ok());
+        assert_eq!(
+            check_adapter(&objects, "hci2").unwrap_err().to_string(),
+            "no such adapter: hci2 (available: hci0, hci1)"
+        );
+    }
+
+    #[test]
+    fn formats() {
+        let style = Style::default();
+        let headset = device("Headset", "audio-headset", Some(80));
+        assert_eq!(headset.long(&style), "🎧 Headset (80%)");
+        assert_eq!(headset.short(&style), "Headset 80%");
+        assert_eq!(headset.narrow(&style), "🎧 80%");
+
+        let unknown = device("Thing", "unknown", None);
+        assert_eq!(unknown.long(&style), "Thing (-)");
+        assert_eq!(unknown.narrow(&style), "-");
+
+        let style = Style {
+            zero_is_unknown: true,
+            ..Style::default()
+        };
+        let empty = device("Keyboard", "input-keyboard", Some(0));
+        assert_eq!(empty.short(&style), "Keyboard -");
+        assert_eq!(empty.narrow(&style), "⌨️ -");
+    }
+
+    #[test]
+    fn format_snapshots() {
+        let plain = Style::default();
+        let i3 = Style {
+            i3: true,
+            ..Style::default()
+        };
+        let span = |glyph: &str| i3.font.span(glyph);
+
+        let cases = [
+            (
+                Device::new("Headset", "audio-headset", Some(80)),
+                ["🎧 Headset (80%)", "Headset 80%", "🎧 80%"],
+                [
+                    format!("{}Headset (80%)", span("headphones")),
+                    format!("{}80%", span("headphones")),
+                ],
+            ),
+            (
+                Device::new("Pad", "input-gaming", Some(5)),
+                ["🎮 Pad (5%)", "Pad 5%", "🎮 5%"],
+                [
+                    format!("{}Pad (5%)", span("sports_esports")),
+                    format!("{}5%", span("sports_esports")),
+                ],
+            ),
+            (
+                Device::new("Phone", "phone", None),
+                ["📱 Phone (-)", "Phone -", "📱 -"],
+                [
+                    format!("{}Phone (-)", span("smartphone")),
+                    format!("{}-", span("smartphone")),
+                ],
+            ),
+            // Unknown icons get no prefix at all.
+            (
+                Device::new("Thing", "unknown", Some(100)),
+                ["Thing (100%)", "Thing 100%", "100%"],
+                ["Thing (100%)".to_string(), "100%".to_string()],
+            ),
+        ];
+
+        for (device, [long, short, narrow], [i3_long, i3_narrow]) in cases {
+            assert_eq!(device.long(&plain), long);
+            assert_eq!(device.short(&plain), short);
+            assert_eq!(device.narrow(&plain), narrow);
+            assert_eq!(device.long(&i3), i3_long);
+            assert_eq!(device.narrow(&i3), i3_narrow);
+        }
+    }
+
+    #[test]
+    fn formats_age_of_cached_devices() {
+        let mut mouse = device("Mouse", "input-mouse", Some(40));
+        mouse.age = Some(Duration::from_secs(180));
+        assert_eq!(mouse.long(&Style::default()), "🖱️ Mouse (40%, 3m ago)");
+        assert_eq!(mouse.short(&Style::default()), "Mouse 40% (3m ago)");
+
+        let style = Style {
+            show_address: true,
+            ..Style::default()
+        };
+        assert_eq!(
+            mouse.long(&style),
+            "🖱️ Mouse (AA:BB:CC:DD:EE:FF, 40%, 3m ago)"
+        );
+        assert_eq!(
+            mouse.short(&style),
+            "Mouse (AA:BB:CC:DD:EE:FF) 40% (3m ago)"
+        );
+
+        mouse.rssi = Some(-52);
+        let style = Style {
+            show_rssi: true,
+            ..Style::default()
+        };
+        assert_eq!(mouse.long(&style), "🖱️ Mouse (40%, -52dBm, 3m ago)");
+
+        mouse.source = Some("HFP".to_string());
+        let style = Style {
+            show_source: true,
+            ..Style::default()
+        };
+        assert_eq!(mouse.long(&style), "🖱️ Mouse (40%, via HFP, 3m ago)");
+
+        mouse.charging = Some(true);
+        let style = Style {
+            show_charging: true,
+            ..Style::default()
+        };
+        assert_eq!(mouse.narrow(&style), "🖱️ 40%⚡ (3m ago)");
+        mouse.charging = Some(false);
+        assert_eq!(mouse.narrow(&style), "🖱️ 40% (3m ago)");
+    }
+
+    #[test]
+    fn uses_icon_map() {
+        let mut style = Style {
+            i3: true,
+            ..Style::default()
+        };
+        let map = "# theme\naudio-headset = <b>H</b>\n"
+            .parse::<config::IconMap>()
+            .unwrap();
+        style.set_icon_map(map.glyphs);
+        let headset = device("Headset", "audio-headset", Some(80));
+        assert_eq!(headset.narrow(&style), "<b>H</b> 80%");
+        assert_eq!(headset.format_with("{icon_emoji}", &style), "<b>H</b> ");
+        assert_eq!(style.material_symbols_name(&headset.icon), Some("<b>H</b>"));
+
+        let unknown = Icon("camera-web".to_string());
+        assert!(!style.knows_icon(&unknown));
+        style.set_icon_map(HashMap::from([(unknown.0.clone(), "C".to_string())]));
+        assert!(style.knows_icon(&unknown));
+        assert_eq!(style.mapped_icons(), ["camera-web"]);
+        assert!("audio-headset".parse::<config::IconMap>().is_err());
+    }
+
+    #[test]
+    fn renders_material_symbols() {
+        let mut style = Style {
+            i3: true,
+            ..Style::default()
+        };
+        let headset = device("Headset", "audio-headset", Some(80));
+        assert_eq!(
+            headset.narrow(&style),
+            "<span font_desc='Material Symbols Outlined @opsz=20,FILL=1,GRAD=-25' rise='-3pt'>headphones</span> 80%"
+        );
+
+        style.font.fill = 0.5;
+        style.font.rise = "0pt".to_string();
+        assert_eq!(
+            headset.icon_only(&style),
+            "<span font_desc='Material Symbols Outlined @opsz=20,FILL=0.5,GRAD=-25' rise='0pt'>headphones</span>"
+        );
+    }
+
+    #[test]
+    fn holds_levels_with_hysteresis() {
+        let thresholds = Thresholds::default();
+        let level = |power, previous| thresholds.level_after(power, Some(previous), 5);
+        assert_eq!(level(51, Level::Normal), Level::Normal);
+        assert_eq!(level(49, Level::Normal), Level::Warning);
+        assert_eq!(level(55, Level::Warning), Level::Warning);
+        assert_eq!(level(56, Level::Warning), Level::Normal);
+        assert_eq!(level(24, Level::Critical), Level::Critical);
+        assert_eq!(level(30, Level::Critical), Level::Warning);
+        assert_eq!(level(52, Level::Critical), Level::Warning);
+        assert_eq!(thresholds.level_after(52, None, 5), Level::Normal);
+    }
+
+    #[test]
+    fn colors_power_with_pango() {
+        let style = Style {
+            i3: true,
+            color: true,
+            ..Style::default()
+        };
+        assert_eq!(style.power(Some(80)), "<span color='#00ff00'>80%</span>");
+        assert_eq!(style.power(Some(50)), "<span color='#ffff00'>50%</span>");
+        assert_eq!(style.power(Some(20)), "<span color='#ff0000'>20%</span>");
+
+        let style = Style {
+            no_color: true,
+            ..style
+        };
+        assert_eq!(style.power(Some(20)), "20%");
+    }
+
+    #[test]
+    fn formats_with_template() {
+        let headset = device("Headset", "audio-headset", Some(80));
+        assert_eq!(
+            headset.format_with("{{{name}}}: {power}", &Style::default()),
+            "{Headset}: 80"
+        );
+        assert_eq!(unknown_placeholders("{name} {nope}"), ["nope"]);
+    }
+
+    #[test]
+    fn uses_thresholds_per_icon() {
+        let config = "[thresholds.input-keyboard]\nwarn = 30 # low enough\n"
+            .parse::<config::Config>()
+            .unwrap();
+        let mut style = Style::default();
+        style.set_icon_thresholds(config.thresholds);
+
+        let keyboard = device("Keyboard", "input-keyboard", Some(40));
+        let mouse = device("Mouse", "input-mouse", Some(40));
+        assert_eq!(keyboard.level(&style), Some(Level::Normal));
+        assert_eq!(mouse.level(&style), Some(Level::Warning));
+        assert_eq!(style.thresholds_for(&keyboard.icon).crit, 20);
+
+        assert!(
+            "[thresholds.phone]\ncrit = 101"
+                .parse::<config::Config>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn battery_icons_follow_thresholds() {
+        let style = Style {
+            battery_icon: true,
+            ..Style::default()
+        };
+        let icon = |power| device("Headset", "audio-headset", power).narrow(&style);
+        assert_eq!(icon(Some(95)), "🔋 95%");
+        assert_eq!(icon(Some(15)), "🪫 15%");
+        assert_eq!(
+            Icon::battery(Some(45), &style.thresholds),
+            Icon("battery-low".to_string())
+        );
+        assert_eq!(
+            Icon::battery(None, &style.thresholds),
+            Icon("battery-missing".to_string())
+        );
+
+        // Held at the warning level by hysteresis, so the icon stays there too.
+        let mut headset = device("Headset", "audio-headset", Some(25));
+        headset.level = Some(Level::Warning);
+        assert_eq!(headset.narrow(&style), "🪫 25%");
+        headset.level = Some(Level::Normal);
+        assert_eq!(headset.narrow(&style), "🔋 25%");
+    }
+
+    #[test]
+    fn bar_covers_whole_range() {
+        let bar = |power| device("Headset", "audio-headset", Some(power)).bar();
+        assert_eq!(bar(0), Some('▁'));
+        assert_eq!(bar(50), Some('▅'));
+        assert_eq!(bar(100), Some('█'));
+    }
+
+    #[test]
+    fn escapes_csv_and_json() {
+        let device = device("Bob's \"Buds\", v2", "audio-headset", None);
+        assert_eq!(
+            device.csv_row(),
+            "\"Bob's \"\"Buds\"\", v2\",audio-headset,"
+        );
+        assert_eq!(
+            Device::new("Left\tRight", "audio-headset", Some(5)).tsv_row(),
+            "Left Right\taudio-headset\t5"
+        );
+        assert_eq!(
+            device.json(false),
+            r#"{"name":"Bob's \"Buds\", v2","icon":"audio-headset","power":null,"connected":true}"#
+        );
+    }
+
+    #[test]
+    fn merges_pairs() {
+        let devices = vec![
+            device("Buds L", "audio-headset", Some(60)),
+            device("Buds (Right)", "audio-headset", Some(41)),
+            device("Mouse", "input-mouse", Some(30)),
+            device("Other R", "audio-headset", Some(90)),
+        ];
+        assert_eq!(pair_base_name("Buds-r"), Some("Buds"));
+        assert_eq!(pair_base_name("Speaker"), None);
+        assert_eq!(pair_base_name("R"), None);
+
+        let merged = merge_pairs(devices, MergeBy::Average);
+        assert_eq!(
+            merged
+                .iter()
+                .map(|d| (d.name.as_str(), d.power))
+                .collect::<Vec<_>>(),
+            [
+                ("Buds", Some(50)),
+                ("Mouse", Some(30)),
+                ("Other R", Some(90))
+            ]
+        );
+    }
+
+    #[test]
+    fn formats_timestamps() {
+        let at = |secs| format_timestamp(std::time::UNIX_EPOCH + Duration::from_secs(secs));
+        assert_eq!(at(0), "1970-01-01T00:00:00Z");
+        assert_eq!(at(951_782_400), "2000-02-29T00:00:00Z");
+        assert_eq!(at(1_700_000_000), "2023-11-14T22:13:20Z");
+    }
+
+    #[test]
+    fn pads_to_display_width() {
+        assert_eq!(display_width("Mouse"), 5);
+        assert_eq!(display_width("🎧 耳机"), 7);
+        assert_eq!(display_width("e\u{301}"), 1);
+        assert_eq!(pad("🎧 80%", 8), "🎧 80%  ");
+        assert_eq!(pad("Keyboard", 3), "Keyboard");
+    }
+
+    #[test]
+    fn formats_numbers_for_locale() {
+        let style = Style {
+            numbers: NumberFormat::for_locale("de_DE.UTF-8"),
+            ..Style::default()
+        };
+        let headset = device("Headset", "audio-headset", Some(80));
+        assert_eq!(headset.short(&style), "Headset 80\u{a0}%");
+        assert_eq!(style.numbers.decimal(62.5, 1), "62,5");
+
+        let numbers = NumberFormat::for_locale("C");
+        assert_eq!(numbers.percent(80), "80%");
+        assert_eq!(numbers.decimal(62.5, 1), "62.5");
+    }
+
+    #[test]
+    fn wraps_json_in_envelope() {
+        let devices = [device("Mouse", "input-mouse", Some(40))];
+        assert_eq!(
+            json_envelope(&devices, false),
+            r#"{"version":1,"devices":[{"name":"Mouse","icon":"input-mouse","power":40,"connected":true}]}"#
+        );
+        assert!(
+            json_envelope(&devices, true)
+                .starts_with("{\n  \"version\": 1,\n  \"devices\": [\n    {\n      \"name\"")
+        );
+    }
+
+    #[test]
+    fn waybar_uses_lowest_power() {
+        let devices = [
+            device("Headset", "audio-headset", Some(80)),
+            device("Mouse", "input-mouse", Some(15)),
+        ];
+        let waybar = Waybar::new(&devices, &Style::default());
+        assert_eq!(waybar.percentage, Some(15));
+        assert_eq!(waybar.class, Some("critical"));
+    }
+
+    #[test]
+    fn waybar_ignores_unknown_power() {
+        let devices = [
+            device("Headset", "audio-headset", Some(80)),
+            device("Mouse", "input-mouse", Some(0)),
+        ];
+        let style = Style {
+            zero_is_unknown: true,
+            ..Style::default()
+        };
+        assert_eq!(Waybar::new(&devices, &style).percentage, Some(80));
+    }
+}
@@ -0,0 +1,38 @@
+//! Messages to stderr, filtered by how much the user asked to see.
+
+use std::sync::atomic::AtomicU8;
+
+/// How much to print to stderr: 0 with `--quiet`, 1 (warnings only) by default, and one more
+/// for each `--verbose`.
+pub static LEVEL: AtomicU8 = AtomicU8::new(1);
+
+/// Print a message to stderr, prefixed with the program name, if the log level is at least
+/// `$level`.
+///
+/// Usage errors should still use `eprintln!`, since they're always worth seeing.
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::log::LEVEL.load(::std::sync::atomic::Ordering::Relaxed) >= $level {
+            eprintln!("{}: {}", env!("CARGO_PKG_NAME"), format_args!($($arg)*));
+        }
+    };
+}
+
+/// Something went wrong, but not badly enough to stop. Silenced by `--quiet`.
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => { $crate::log!(1, $($arg)*) };
+}
+
+/// What's being asked of BlueZ, and what it answered. Shown with `-v`.
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => { $crate::log!(2, $($arg)*) };
+}
+
+/// Every detail, e.g. each object BlueZ reported. Shown with `-vv`.
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => { $crate::log!(3, $($arg)*) };
+}
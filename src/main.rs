@@ -1,14 +1,42 @@
-use std::time::Duration;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{FileTypeExt, OpenOptionsExt};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, PoisonError, mpsc};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use dbus::arg::PropMap;
 use dbus::blocking::Connection;
-use dbus::blocking::stdintf::org_freedesktop_dbus::{ObjectManager, Properties};
+use dbus::blocking::stdintf::org_freedesktop_dbus::{
+    ObjectManagerInterfacesAdded, ObjectManagerInterfacesRemoved, PropertiesPropertiesChanged,
+};
+use dbus::message::SignalArgs;
+use dbus::strings::BusName;
+use dbus::{Message, Path};
+
+use bluetooth_battery::cache::Cache;
+use bluetooth_battery::config::{Config, DeviceList, IconMap};
+use bluetooth_battery::{
+    BLUEZ_SERVICE, BUILTIN_ICONS, BatterySource, BlueZ, Device, DeviceFormat, Icon, Level,
+    ManagedObjects, MergeBy, NumberFormat, Style, Trend, UNKNOWN_POWER, UPowerBattery, Waybar,
+    check_adapter, debug, device_from_interfaces, device_path, devices_from_objects, fetch_device,
+    fetch_devices_one_by_one, format_timestamp, is_on_adapter, is_service_unknown, is_timeout,
+    is_valid_address, json_array, json_envelope, json_string, log, merge_pairs, pad,
+    prometheus_metrics, resolve_address, summary, trace, unknown_placeholders, warn,
+};
 
 const USAGE_MESSAGE: &str = concat!(
     "Usage: ",
     env!("CARGO_BIN_NAME"),
-    " [-3hlnsV] [--help | --i3 | --long | --narrow | --pango | --short | --usage | --version] [DEVICE]..."
+    " [-3hlnqsvV] [--help | --i3 | --json | --json-pretty | --ndjson | --long | --narrow | --icon-only | --pango | --short | --usage | --version | --waybar | --with-tooltip | --csv | --tsv | --tmux | --polybar | --prometheus | --summary | --raw | --count] [--output FILE] [--no-newline] [--timestamp] [--csv-header] [--json-envelope] [--format-name NAME] [--format TEMPLATE] [--long-template TEMPLATE] [--separator STR] [--pad-width N] [--adapter ADAPTER] [--service NAME] [--session-bus] [--timeout MILLIS] [--retries N] [--notify [PERCENT] [--notify-interval SECONDS]] [--opsz N] [--fill N] [--grad N] [--rise SIZE] [--nerd] [--battery-icon] [--no-icon | --default-icon STR] [--compact] [--show-address] [--show-rssi] [--show-source] [--show-charging] [--strict-icons] [--locale LOCALE] [--color] [--show-age] [--bar [--show-percent]] [--warn PERCENT] [--crit PERCENT] [--hysteresis N] [--trend] [--fail-below PERCENT] [--status-exit] [--only-below PERCENT] [--clamp] [--round STEP] [--zero-is-unknown] [--alias ADDRESS=NAME]... [--name SUBSTRING]... [--merge-pairs [HOW]] [--sort KEY] [--reverse] [--pin-order] [--preserve-arg-order] [--lowest] [--limit N] [--all] [--show-disconnected] [--require-device] [--upower-fallback] [--dry-run] [--check] [--explain] [--list-icons] [--devices-file PATH] [--icon-map FILE] [--cache PATH [--cache-ttl SECONDS]] [--monitor [--max-stale SECONDS] | --watch SECONDS [--on-change-only]] [-] [DEVICE]..."
 );
 
+/// The single-character flags that can be combined, e.g. `-3l`.
+const SHORT_FLAGS: &str = "3hlnqsvV";
+
 const HELP_MESSAGE_FRAGMENT: &str = "Show the battery life of connected bluetooth devices.
 
 Use -h or --help to show this help message.
@@ -16,7 +44,12 @@ Use -h or --help to show this help message.
 Project home page: https://github.com/mklein994/bluetooth-battery
 
 POSITIONAL ARGUMENTS:
-  [DEVICE]...  The bluetooth device's address, e.g. AA:BB:CC:DD:EE:FF.
+  [DEVICE]...  The bluetooth device's address, e.g. AA:BB:CC:DD:EE:FF, or its
+               BlueZ object path, e.g. /org/bluez/hci0/dev_AA_BB_CC_DD_EE_FF.
+               The end of an address, e.g. DD:EE:FF, stands for the one
+               device whose address ends that way.
+  -            Also read addresses from stdin, one per line. Blank lines and
+               lines starting with # are ignored.
 
 FORMAT OPTIONS:
   -3, --i3       Format for i3blocks using pango markup.
@@ -24,250 +57,2753 @@ FORMAT OPTIONS:
   -l, --long     Use a long format (icon, name, percentage).
   -s, --short    Use a short format (name, percentage).
   -n, --narrow   Use a narrow format (icon, percentage). This is the default.
+  --icon-only    Only show each device's icon.
+  --format-name NAME
+                 Use the format called NAME: long, short, narrow or icon-only.
+  --opsz N       With --i3, the optical size of Material Symbols icons
+                 (default: 20).
+  --fill N       With --i3, how filled in Material Symbols icons are, from 0
+                 to 1 (default: 1).
+  --grad N       With --i3, the grade of Material Symbols icons, from -25 to
+                 200 (default: -25).
+  --rise SIZE    With --i3, how far to raise Material Symbols icons, e.g. 2pt
+                 (default: -3pt).
+  --nerd         Use Nerd Font glyphs for icons instead of emoji or Material
+                 Symbols.
+  --battery-icon Show an icon for the battery level (full, good, low, caution
+                 or empty, according to --warn and --crit) instead of the kind
+                 of device. These can be overridden in the configuration file
+                 as icons named battery-full, battery-low and so on.
+  --no-icon      Don't show icons in any format, e.g. if the font doesn't have
+                 them.
+  --default-icon STR
+                 Show STR for devices without an icon, instead of nothing. It
+                 isn't changed for --i3, so it can be pango markup.
+  --show-address With --long or --short, show each device's address too, to
+                 tell apart devices with the same name.
+  --show-rssi    With --long, show each device's signal strength too, when
+                 BlueZ knows it.
+  --show-source  With --long, show where each device's battery level came
+                 from too, e.g. HFP, for devices that BlueZ reads it from
+                 battery providers.
+  --show-charging
+                 Show ⚡ (or with --i3, a bolt) after the battery level of
+                 devices that UPower says are charging. With --monitor, this
+                 is read again after each change in BlueZ, or every minute.
+  --strict-icons Warn about devices whose icon has no emoji or Material Symbols
+                 glyph, naming the icon, so that one can be added.
+  --locale LOCALE
+                 Write percentages the way LOCALE does, e.g. with a space
+                 before the percent sign for de_DE (default: from the
+                 environment, see below).
+  --compact      With --narrow, don't put a space between the icon and the
+                 battery level.
+  --color        With --i3, color the percentage according to --warn and
+                 --crit.
+  --show-age     With --long, show how long ago each battery level was read.
+  --bar          With --narrow, show the battery level as a bar (▁ to █)
+                 instead of a percentage.
+  --show-percent With --bar, show the percentage after the bar.
+  --zero-is-unknown
+                 Show a battery level of 0% as unknown (-), for devices that
+                 report 0% until they've measured it.
+  --clamp        Show battery levels over 100 as 100, for devices that report
+                 nonsense. Without it, they're shown as they are.
+  --round STEP   Round battery levels to the nearest multiple of STEP, so that
+                 they change less often. --only-below and the like see the
+                 rounded level too.
+  --warn PERCENT
+                 The battery level at or below which a device is low
+                 (default: 50).
+  --crit PERCENT
+                 The battery level at or below which a device is critically
+                 low (default: 20).
+  --hysteresis N With --monitor or --watch, keep showing a device as low (or
+                 critically low) until its battery is more than N above
+                 --warn (or --crit), so that it doesn't flicker between them.
+  --trend        With --monitor or --watch, show an arrow after each device
+                 for whether its battery went up (↑) or down (↓) the last time
+                 it changed, or → if it hasn't yet.
+  --json         Print the devices as a single-line JSON array.
+  --json-pretty  Like --json, but pretty-printed.
+  --json-envelope
+                 With --json or --json-pretty, print an object with the
+                 version of its format and the array of devices, e.g.
+                 {\"version\": 1, \"devices\": [...]}.
+  --ndjson       Print a single-line JSON object with a timestamp (in seconds
+                 since the Unix epoch) and the devices. Best combined with
+                 --monitor, to get one object per line for every change.
+  --waybar       Print a JSON object for a Waybar custom module.
+  --with-tooltip Print the devices in the narrow format on one line, and in
+                 the long format on a second line, for bars that take a
+                 tooltip that way.
+  --tmux         Print the name and percentage of each device, colored using
+                 tmux status line styles according to --warn and --crit.
+  --raw          Print just the battery level of a single device, without a
+                 percent sign.
+  --count        Print how many devices there are to show.
+  --summary      Print one line per kind of device, with how many there are and
+                 the lowest battery level among them.
+  --polybar      Print the icon, a battery glyph and percentage of each device,
+                 colored using polybar format tags according to --warn and
+                 --crit. The battery glyphs are from Font Awesome.
+  --prometheus   Print the battery levels as Prometheus metrics, e.g. for
+                 node_exporter's textfile collector.
+  --csv          Print one CSV row per device, with name, icon and power
+                 columns.
+  --tsv          Print one line per device, with tab-separated name, icon and
+                 power fields. Tabs in names are replaced with spaces.
+  --csv-header   With --csv or --tsv, print a header row first.
+  --format TEMPLATE
+                 Use a custom format. Available placeholders are {name},
+                 {address}, {power}, {icon} and {icon_emoji}. Use {{ and }}
+                 for literal braces. Overrides --long, --short and --narrow.
+  --long-template TEMPLATE
+                 Like --format, but only for devices shown in the long format,
+                 e.g. \"{icon}{name} — {power}%\".
+  --separator STR
+                 Put STR between devices instead of a space (or two spaces for
+                 --short). \\n and \\t are replaced by a newline and a tab.
+  --pad-width N  Pad each device with spaces to at least N columns, counting
+                 emoji and CJK characters as two, so that they line up. Pango
+                 markup from --i3 is counted too.
 
 OTHER OPTIONS:
+  --no-newline   Don't print a newline after the output, except between lines
+                 with --monitor and --watch.
+  --timestamp    Start each line with the time in UTC, e.g.
+                 [2024-05-01T12:34:56Z]. For --ndjson, --waybar and
+                 --json-envelope, add it as a time field instead, for --csv and
+                 --tsv as a first column, and for --prometheus as the time of
+                 each sample. --json and --json-pretty without --json-envelope
+                 are left as is.
+  --output FILE  Write the output to FILE instead of stdout, replacing it
+                 each time with --monitor or --watch. If FILE is a named pipe,
+                 each update is written to it instead, waiting for a reader
+                 and reopening it whenever the reader goes away.
+  --adapter ADAPTER
+                 The adapter that DEVICE addresses belong to, e.g. hci1.
+                 Defaults to hci0. Without any DEVICE, only show devices on
+                 this adapter.
+  --service NAME Talk to NAME on the system bus instead of org.bluez, e.g. a
+                 stub service for testing.
+  --session-bus  Use the session bus instead of the system bus, e.g. to test
+                 against a fake service under dbus-run-session.
+  --timeout MILLIS
+                 How long to wait for BlueZ to answer, in milliseconds
+                 (default: 5000). If listing every device times out, they're
+                 read one at a time instead, skipping those that don't answer.
+  --retries N    If connecting to DBus or reading the devices fails, try again
+                 up to N times, waiting longer each time (default: 0). With
+                 --monitor and --watch, only the first reading is retried.
+  --notify [PERCENT]
+                 Send a desktop notification when a device's battery is at or
+                 below PERCENT (default: 20). Best combined with --monitor or
+                 --watch. A number up to 100 after it is always the PERCENT,
+                 so to follow it with the end of an address like 12, write
+                 --notify=PERCENT 12.
+  --notify-interval SECONDS
+                 With --notify, don't notify about a device again within
+                 SECONDS of the last time, even if its battery went back up
+                 and down in between.
+  --fail-below PERCENT
+                 Exit with status 2 if any device's battery is below PERCENT.
+  --status-exit  Exit with status 1 if any device's battery is at or below
+                 --warn, or 2 if any is at or below --crit.
+  --only-below PERCENT
+                 Only show devices whose battery is below PERCENT.
+  --all          Also show paired devices that aren't connected, if BlueZ still
+                 knows their last battery level.
+  --show-disconnected
+                 Also show paired devices that aren't connected, with - in
+                 place of their battery level.
+  --require-device
+                 Exit with status 3 if there are no devices to show.
+  --upower-fallback
+                 For connected devices that don't report a battery level to
+                 BlueZ, look for one in UPower.
+  --dry-run      Print the object paths and properties that would be read from
+                 BlueZ, and exit without connecting to it.
+  --check        Print ok if the system bus and BlueZ can be reached, without
+                 showing any devices. Otherwise, say why and exit with a
+                 non-zero status.
+  --explain      Print every property read for each DEVICE, with its DBus type,
+                 and the device built from them, then exit.
+  --list-icons   Print each icon name with a built-in glyph or one from
+                 --icon-map, along with its emoji and Material Symbols glyph
+                 (as overridden by the configuration file and --icon-map),
+                 then exit.
+  --devices-file PATH
+                 Read the devices to show when none are given from PATH
+                 instead of the devices file below.
+  --icon-map FILE
+                 Use the glyphs in FILE for icons, e.g. for an icon theme,
+                 instead of the built-in ones or those from the configuration
+                 file. It has one ICON = GLYPH per line, with freedesktop icon
+                 names, e.g. audio-headset = 🎧. Glyphs are used as is, so they
+                 can be pango markup with --i3.
+  --cache PATH   Remember the last battery levels in PATH, and show them for
+                 devices that briefly disappear, along with their age.
+  --cache-ttl SECONDS
+                 How long cached battery levels are shown for (default: 300).
+  --alias ADDRESS=NAME
+                 Show the device with ADDRESS as NAME. Can be repeated.
+  --name SUBSTRING
+                 Only show devices whose name or alias contains SUBSTRING,
+                 ignoring case, as well as any DEVICE addresses. Can be
+                 repeated.
+  --merge-pairs [HOW]
+                 Show the halves of a pair, like \"Buds L\" and \"Buds R\", as one
+                 device. HOW is min (the default) to show the lowest battery
+                 level of the two, or average.
+  --sort KEY     Sort devices by KEY, one of name, power or icon. By default,
+                 devices are sorted by name, then icon, then power.
+  --reverse      Reverse the sort order.
+  --pin-order    Show the devices listed in the configuration file's order
+                 first, in that order, followed by any others.
+  --preserve-arg-order
+                 Show the devices given as DEVICE in the order they're given,
+                 instead of sorting them, followed by any others picked by
+                 --name.
+  --lowest       Only show the device with the lowest battery level. If several
+                 are equally low, the first in sort order is shown.
+  --limit N      Only show the first N devices in sort order, e.g. the three
+                 lowest with --sort power --limit 3.
+  --monitor      Keep running, printing a new line whenever a device changes.
+                 Send it SIGHUP to read the configuration files again.
+  --max-stale SECONDS
+                 With --monitor, query BlueZ again and print a line at least
+                 every SECONDS seconds, even if no changes were reported.
+  --watch SECONDS
+                 Keep running, printing a new line every SECONDS seconds.
+  --on-change-only
+                 With --watch, only print a line if it's different from the
+                 last one.
+  -q, --quiet    Don't print warnings to stderr. If the bluetooth service isn't
+                 running, the exit status is still 4.
+  -v, --verbose  Print what's asked of BlueZ, and why devices are hidden, to
+                 stderr. Can be repeated for more detail.
   -h, --usage    Print a short usage message.
   --help         Print this full help message.
-  -V, --version  Print the version.";
+  -V, --version  Print the version.
+
+Exits with status 4 if the bluetooth service (org.bluez) isn't running.
+
+ENVIRONMENT:
+  BLUETOOTH_BATTERY_FORMAT
+                 The device format to use when none of --long, --short,
+                 --narrow, --icon-only or --format-name are given.
+  LC_ALL, LC_NUMERIC, LANG
+                 The first of these that's set is the locale to write
+                 percentages in, unless --locale is given.
+  NO_COLOR       If set to a non-empty value, never add colors, even with
+                 --color, --tmux or --polybar.
+
+FILES:
+  $XDG_CONFIG_HOME/bluetooth-battery/config.toml
+                 Optional configuration, read from ~/.config if XDG_CONFIG_HOME
+                 isn't set. Icons can be added or overridden per freedesktop
+                 icon name, for example:
+
+                   [icons.watch]
+                   emoji = \"⌚\"
+                   material_symbols = \"watch\"
+
+                 Devices can be given aliases by address, which --alias
+                 overrides:
+
+                   [aliases]
+                   \"AA:BB:CC:DD:EE:FF\" = \"Earbuds\"
+
+                 The order used by --pin-order is a list of addresses, given
+                 before any tables:
+
+                   order = [\"AA:BB:CC:DD:EE:FF\", \"11:22:33:44:55:66\"]
+
+                 Devices can be shown in a different format by icon name,
+                 falling back to the one from the command line:
+
+                   [formats]
+                   audio-headset = \"long\"
+                   input-mouse = \"narrow\"
+
+                 They can also have their own --warn and --crit thresholds:
+
+                   [thresholds.input-keyboard]
+                   warn = 20
+                   crit = 5
+
+  $XDG_CONFIG_HOME/bluetooth-battery/devices
+                 Optional list of devices to show when no DEVICE is given,
+                 one address per line with an optional alias:
+
+                   AA:BB:CC:DD:EE:FF = Earbuds
+                   11:22:33:44:55:66
+
+                 Without it, every device with a battery is shown.";
 
 #[derive(Default)]
 struct Opt {
-    fmt: DeviceFormat,
-    i3: bool,
+    /// The format from the command line, or else from `BLUETOOTH_BATTERY_FORMAT`.
+    fmt: Option<DeviceFormat>,
+    strict_icons: bool,
+    /// The locale to write numbers in, from `--locale`, or else from the environment.
+    locale: Option<String>,
+    preserve_arg_order: bool,
+    session_bus: bool,
+    /// Formats from the configuration file, keyed by icon name, which take precedence over `fmt`.
+    formats: HashMap<String, DeviceFormat>,
+    output: OutputMode,
+    style: Style,
+    csv_header: bool,
+    json_envelope: bool,
+    /// Where to write the output instead of stdout, from `--output`.
+    output_file: Option<PathBuf>,
+    no_newline: bool,
+    timestamp: bool,
+    template: Option<String>,
+    /// Used instead of the built-in layout for the long format.
+    long_template: Option<String>,
+    separator: Option<String>,
+    pad_width: Option<usize>,
+    watch: Option<Duration>,
+    on_change_only: bool,
+    monitor: bool,
+    max_stale: Option<Duration>,
+    adapter: Option<String>,
+    /// The bus name to talk to instead of BlueZ's, from `--service`.
+    service: Option<String>,
+    timeout: Option<Duration>,
+    retries: u32,
+    notify: Option<u64>,
+    notify_interval: Option<Duration>,
+    fail_below: Option<u64>,
+    status_exit: bool,
+    only_below: Option<u64>,
+    hysteresis: Option<u64>,
+    trend: bool,
+    clamp: bool,
+    round: Option<u64>,
+    all: bool,
+    show_disconnected: bool,
+    require_device: bool,
+    dry_run: bool,
+    check: bool,
+    upower_fallback: bool,
+    cache: Option<PathBuf>,
+    list_icons: bool,
+    explain: bool,
+    /// The list of devices to show by default, from `--devices-file`.
+    devices_file: Option<PathBuf>,
+    icon_map: Option<PathBuf>,
+    cache_ttl: Option<Duration>,
+    merge_pairs: Option<MergeBy>,
+    sort: Option<SortKey>,
+    reverse: bool,
+    lowest: bool,
+    limit: Option<usize>,
+    pin_order: bool,
+    /// Upper case addresses from the configuration file, in the order `--pin-order` shows them.
+    order: Vec<String>,
+    /// Names to show instead of the ones from BlueZ, keyed by upper case address.
+    aliases: HashMap<String, String>,
+    /// Just the aliases from `--alias`, which take precedence over the configuration file's.
+    cli_aliases: HashMap<String, String>,
+    /// Parts of device names to show, from `--name`.
+    names: Vec<String>,
+    quiet: bool,
+    verbose: u8,
+    /// Read more addresses from stdin, as requested with `-`.
+    stdin: bool,
     addresses: Vec<String>,
+    /// Whether `addresses` came from the devices file, and so are replaced when it's reloaded.
+    addresses_from_file: bool,
+    /// The ends of addresses, resolved into `addresses` once BlueZ can be asked.
+    partial_addresses: Vec<String>,
+    /// BlueZ object paths of devices, used as they are.
+    paths: Vec<String>,
 }
 
 impl Opt {
-    fn from_args(args: impl ExactSizeIterator<Item = String>) -> Self {
+    fn from_args(args: impl ExactSizeIterator<Item = String>) -> Result<Self, OptError> {
         let mut opt = Self::default();
-        for arg in args {
+        let mut args = args.peekable();
+        // The flags from a cluster like `-ls`, still to be handled.
+        let mut clustered = VecDeque::new();
+        while let Some(arg) = clustered.pop_front().or_else(|| args.next()) {
             match arg.as_str() {
                 "-s" | "--short" => {
-                    opt.fmt = DeviceFormat::Short;
+                    opt.fmt = Some(DeviceFormat::Short);
                 }
                 "-l" | "--long" => {
-                    opt.fmt = DeviceFormat::Long;
+                    opt.fmt = Some(DeviceFormat::Long);
                 }
                 "-n" | "--narrow" => {
-                    opt.fmt = DeviceFormat::Narrow;
+                    opt.fmt = Some(DeviceFormat::Narrow);
+                }
+                "--icon-only" => {
+                    opt.fmt = Some(DeviceFormat::IconOnly);
+                }
+                "--format-name" => {
+                    opt.fmt = Some(parse_value(&arg, &required_value(&arg, &mut args)?)?);
+                }
+                "--json" => {
+                    opt.output = OutputMode::Json;
+                }
+                "--json-envelope" => {
+                    opt.json_envelope = true;
+                }
+                "--ndjson" => {
+                    opt.output = OutputMode::Ndjson;
+                }
+                "--json-pretty" => {
+                    opt.output = OutputMode::JsonPretty;
+                }
+                "--waybar" => {
+                    opt.output = OutputMode::Waybar;
+                }
+                "--with-tooltip" => {
+                    opt.output = OutputMode::Tooltip;
+                }
+                "--count" => {
+                    opt.output = OutputMode::Count;
+                }
+                "--raw" => {
+                    opt.output = OutputMode::Raw;
+                }
+                "--summary" => {
+                    opt.output = OutputMode::Summary;
+                }
+                "--prometheus" => {
+                    opt.output = OutputMode::Prometheus;
+                }
+                "--polybar" => {
+                    opt.output = OutputMode::Polybar;
+                }
+                "--tmux" => {
+                    opt.output = OutputMode::Tmux;
+                }
+                "--csv" => {
+                    opt.output = OutputMode::Csv;
+                }
+                "--tsv" => {
+                    opt.output = OutputMode::Tsv;
+                }
+                "--output" => {
+                    opt.output_file = Some(required_value(&arg, &mut args)?.into());
+                }
+                "--timestamp" => {
+                    opt.timestamp = true;
+                }
+                "--no-newline" => {
+                    opt.no_newline = true;
+                }
+                "--csv-header" => {
+                    opt.csv_header = true;
+                }
+                "--format" => {
+                    let template = required_value(&arg, &mut args)?;
+                    if let Some(unknown) = unknown_placeholders(&template).first() {
+                        return Err(OptError::Invalid(format!(
+                            "unknown placeholder in --format: {{{unknown}}}"
+                        )));
+                    }
+                    opt.template = Some(template);
+                }
+                "--long-template" => {
+                    let template = required_value(&arg, &mut args)?;
+                    if let Some(unknown) = unknown_placeholders(&template).first() {
+                        return Err(OptError::Invalid(format!(
+                            "unknown placeholder in --long-template: {{{unknown}}}"
+                        )));
+                    }
+                    opt.long_template = Some(template);
+                }
+                "--adapter" => {
+                    opt.adapter = Some(required_value(&arg, &mut args)?);
+                }
+                "--service" => {
+                    let value = required_value(&arg, &mut args)?;
+                    if let Err(e) = BusName::new(value.as_str()) {
+                        return Err(OptError::Invalid(format!(
+                            "invalid value for {arg}: '{value}': {e}"
+                        )));
+                    }
+                    opt.service = Some(value);
+                }
+                "--session-bus" => {
+                    opt.session_bus = true;
+                }
+                "--timeout" => {
+                    let millis: u64 = parse_value(&arg, &required_value(&arg, &mut args)?)?;
+                    if millis == 0 {
+                        return Err(OptError::Invalid(
+                            "--timeout must be at least 1".to_string(),
+                        ));
+                    }
+                    opt.timeout = Some(Duration::from_millis(millis));
+                }
+                "--retries" => {
+                    opt.retries = parse_value(&arg, &required_value(&arg, &mut args)?)?;
+                }
+                "--notify" => {
+                    // Two digits could also be the end of an address, which can follow
+                    // `--notify=PERCENT` instead.
+                    let is_percent = |x: &String| x.parse::<u64>().is_ok_and(|x| x <= 100);
+                    opt.notify = Some(match args.next_if(is_percent) {
+                        Some(threshold) => parse_value(&arg, &threshold)?,
+                        None => 20,
+                    });
+                }
+                x if let Some(threshold) = x.strip_prefix("--notify=") => {
+                    opt.notify = Some(parse_value("--notify", threshold)?);
+                }
+                "--notify-interval" => {
+                    let seconds: u64 = parse_value(&arg, &required_value(&arg, &mut args)?)?;
+                    if seconds == 0 {
+                        return Err(OptError::Invalid(
+                            "--notify-interval must be at least 1".to_string(),
+                        ));
+                    }
+                    opt.notify_interval = Some(Duration::from_secs(seconds));
+                }
+                "--opsz" => {
+                    opt.style.font.opsz = parse_value(&arg, &required_value(&arg, &mut args)?)?;
+                }
+                "--fill" => {
+                    let fill: f32 = parse_value(&arg, &required_value(&arg, &mut args)?)?;
+                    if !(0.0..=1.0).contains(&fill) {
+                        return Err(OptError::Invalid("--fill must be from 0 to 1".to_string()));
+                    }
+                    opt.style.font.fill = fill;
+                }
+                "--grad" => {
+                    opt.style.font.grad = parse_value(&arg, &required_value(&arg, &mut args)?)?;
+                }
+                "--rise" => {
+                    opt.style.font.rise = required_value(&arg, &mut args)?;
+                }
+                "--nerd" => {
+                    opt.style.nerd = true;
+                }
+                "--strict-icons" => {
+                    opt.strict_icons = true;
+                }
+                "--show-charging" => {
+                    opt.style.show_charging = true;
+                }
+                "--show-source" => {
+                    opt.style.show_source = true;
+                }
+                "--show-rssi" => {
+                    opt.style.show_rssi = true;
+                }
+                "--show-address" => {
+                    opt.style.show_address = true;
+                }
+                "--locale" => {
+                    opt.locale = Some(required_value(&arg, &mut args)?);
+                }
+                "--compact" => {
+                    opt.style.compact = true;
+                }
+                "--no-icon" => {
+                    opt.style.no_icon = true;
+                }
+                "--default-icon" => {
+                    opt.style.default_icon = Some(required_value(&arg, &mut args)?);
+                }
+                "--battery-icon" => {
+                    opt.style.battery_icon = true;
+                }
+                "--show-age" => {
+                    opt.style.show_age = true;
+                }
+                "--bar" => {
+                    opt.style.bar = true;
+                }
+                "--show-percent" => {
+                    opt.style.show_percent = true;
+                }
+                "--color" => {
+                    opt.style.color = true;
+                }
+                "--warn" => {
+                    opt.style.thresholds.warn =
+                        parse_value(&arg, &required_value(&arg, &mut args)?)?;
+                }
+                "--crit" => {
+                    opt.style.thresholds.crit =
+                        parse_value(&arg, &required_value(&arg, &mut args)?)?;
+                }
+                "--hysteresis" => {
+                    opt.hysteresis = Some(parse_value(&arg, &required_value(&arg, &mut args)?)?);
+                }
+                "--trend" => {
+                    opt.trend = true;
+                }
+                "--fail-below" => {
+                    opt.fail_below = Some(parse_value(&arg, &required_value(&arg, &mut args)?)?);
+                }
+                "--status-exit" => {
+                    opt.status_exit = true;
+                }
+                "--only-below" => {
+                    opt.only_below = Some(parse_value(&arg, &required_value(&arg, &mut args)?)?);
+                }
+                "--all" => {
+                    opt.all = true;
+                }
+                "--show-disconnected" => {
+                    opt.show_disconnected = true;
+                }
+                "--require-device" => {
+                    opt.require_device = true;
+                }
+                "--upower-fallback" => {
+                    opt.upower_fallback = true;
+                }
+                "--check" => {
+                    opt.check = true;
+                }
+                "--dry-run" => {
+                    opt.dry_run = true;
+                }
+                "--explain" => {
+                    opt.explain = true;
+                }
+                "--list-icons" => {
+                    opt.list_icons = true;
+                }
+                "--devices-file" => {
+                    opt.devices_file = Some(required_value(&arg, &mut args)?.into());
+                }
+                "--icon-map" => {
+                    opt.icon_map = Some(required_value(&arg, &mut args)?.into());
+                }
+                "--cache" => {
+                    opt.cache = Some(required_value(&arg, &mut args)?.into());
+                }
+                "--cache-ttl" => {
+                    let seconds = parse_value(&arg, &required_value(&arg, &mut args)?)?;
+                    opt.cache_ttl = Some(Duration::from_secs(seconds));
+                }
+                "--alias" => {
+                    let value = required_value(&arg, &mut args)?;
+                    let Some((address, alias)) = value.split_once('=') else {
+                        return Err(OptError::Invalid(format!(
+                            "expected ADDRESS=NAME for {arg}: '{value}'"
+                        )));
+                    };
+                    if !is_valid_address(address) {
+                        return Err(OptError::Invalid(format!(
+                            "invalid bluetooth address for {arg}: '{address}' (expected e.g. AA:BB:CC:DD:EE:FF)"
+                        )));
+                    }
+                    opt.aliases
+                        .insert(address.to_ascii_uppercase(), alias.to_string());
+                }
+                "--name" => {
+                    opt.names.push(required_value(&arg, &mut args)?);
+                }
+                "--merge-pairs" => {
+                    opt.merge_pairs = Some(match args.next_if(|x| x.parse::<MergeBy>().is_ok()) {
+                        Some(how) => parse_value(&arg, &how)?,
+                        None => MergeBy::Min,
+                    });
+                }
+                "--sort" => {
+                    opt.sort = Some(parse_value(&arg, &required_value(&arg, &mut args)?)?);
+                }
+                "--reverse" => {
+                    opt.reverse = true;
+                }
+                "--preserve-arg-order" => {
+                    opt.preserve_arg_order = true;
+                }
+                "--pin-order" => {
+                    opt.pin_order = true;
+                }
+                "--limit" => {
+                    opt.limit = Some(parse_value(&arg, &required_value(&arg, &mut args)?)?);
+                }
+                "--lowest" => {
+                    opt.lowest = true;
+                }
+                "--monitor" => {
+                    opt.monitor = true;
+                }
+                "--max-stale" => {
+                    let seconds = parse_value(&arg, &required_value(&arg, &mut args)?)?;
+                    opt.max_stale = Some(Duration::from_secs(seconds));
+                }
+                "--on-change-only" => {
+                    opt.on_change_only = true;
+                }
+                "--watch" => {
+                    let seconds: u64 = parse_value(&arg, &required_value(&arg, &mut args)?)?;
+                    if seconds == 0 {
+                        return Err(OptError::Invalid("--watch must be at least 1".to_string()));
+                    }
+                    opt.watch = Some(Duration::from_secs(seconds));
+                }
+                "--zero-is-unknown" => {
+                    opt.style.zero_is_unknown = true;
+                }
+                "--clamp" => {
+                    opt.clamp = true;
+                }
+                "--round" => {
+                    let step: u64 = parse_value(&arg, &required_value(&arg, &mut args)?)?;
+                    if step == 0 {
+                        return Err(OptError::Invalid("--round must be at least 1".to_string()));
+                    }
+                    opt.round = Some(step);
+                }
+                "--pad-width" => {
+                    opt.pad_width = Some(parse_value(&arg, &required_value(&arg, &mut args)?)?);
+                }
+                "--separator" => {
+                    opt.separator = Some(unescape(&required_value(&arg, &mut args)?));
                 }
                 "-3" | "--i3" | "--pango" => {
-                    opt.i3 = true;
+                    opt.style.i3 = true;
+                }
+                "-q" | "--quiet" => {
+                    opt.quiet = true;
                 }
-                "-h" | "--usage" => {
-                    println!("{USAGE_MESSAGE}");
-                    std::process::exit(0);
+                "-v" | "--verbose" => {
+                    opt.verbose += 1;
                 }
-                "--help" => {
-                    println!("{USAGE_MESSAGE}\n\n{HELP_MESSAGE_FRAGMENT}");
-                    std::process::exit(0);
+                "-h" | "--usage" => return Err(OptError::Usage),
+                "--help" => return Err(OptError::Help),
+                "-V" | "--version" => return Err(OptError::Version),
+                x if is_short_flag_cluster(x) => {
+                    clustered.extend(x.chars().skip(1).map(|c| format!("-{c}")));
                 }
-                "-V" | "--version" => {
-                    println!("{} {}", env!("CARGO_BIN_NAME"), env!("CARGO_PKG_VERSION"));
-                    std::process::exit(0);
+                "-" => {
+                    opt.stdin = true;
                 }
-                x if x.contains(|c: char| c.is_ascii_hexdigit() || c == ':') => {
-                    opt.addresses.push(arg);
+                x if x.starts_with("/org/bluez/") => {
+                    opt.paths.push(arg);
                 }
-                _ => {
-                    eprintln!("{USAGE_MESSAGE}");
-                    std::process::exit(1);
+                x if !x.starts_with('-') => {
+                    opt.add_address(x)?;
                 }
+                _ => return Err(OptError::Unknown),
             }
         }
 
-        opt
+        Ok(opt)
     }
-}
-
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let opt = Opt::from_args(std::env::args().skip(1));
-
-    let conn = Connection::new_system()?;
-    let timeout = Duration::from_secs(5);
-
-    let mut devices = if opt.addresses.is_empty() {
-        let proxy = conn.with_proxy("org.bluez", "/", timeout);
-
-        let objects = proxy.get_managed_objects()?;
-
-        objects
-            .into_values()
-            .filter_map(|v| {
-                let device = v.get("org.bluez.Device1")?;
-                let connected = device
-                    .get("Connected")
-                    .and_then(|x| x.0.as_u64())
-                    .is_some_and(|x| x != 0);
-                let name = device.get("Name").and_then(|x| x.0.as_str())?.to_string();
-                let icon = device
-                    .get("Icon")
-                    .and_then(|x| x.0.as_str())?
-                    .parse()
-                    .ok()?;
-                let power = v
-                    .get("org.bluez.Battery1")
-                    .and_then(|x| x.get("Percentage"))
-                    .and_then(|x| x.0.as_u64())?;
-
-                connected.then_some(Device { name, icon, power })
-            })
-            .collect()
-    } else {
-        let mut device_list = vec![];
-        for address in opt.addresses {
-            let path = format!(
-                "/org/bluez/hci0/dev_{}",
-                address.to_ascii_uppercase().replace(':', "_")
-            );
-            let proxy = conn.with_proxy("org.bluez", path, timeout);
 
-            let connected: bool = proxy.get("org.bluez.Device1", "Connected")?;
-            if !connected {
+    /// Add the addresses listed in `reader`, one per line, skipping blank lines and `#` comments.
+    fn read_addresses(&mut self, reader: impl BufRead) -> Result<(), OptError> {
+        for line in reader.lines() {
+            let line = line.map_err(|e| OptError::Invalid(format!("failed to read stdin: {e}")))?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
                 continue;
             }
+            self.add_address(line)?;
+        }
+        Ok(())
+    }
+
+    /// Read the configuration and devices files, on top of the options from the command line.
+    ///
+    /// This can be called again to pick up changes to the files.
+    fn load_config(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.load_config_from(Config::path().as_deref(), DeviceList::path().as_deref())
+    }
 
-            let power: u8 = proxy.get("org.bluez.Battery1", "Percentage")?;
-            let name: String = proxy.get("org.bluez.Device1", "Name")?;
-            let icon: String = proxy.get("org.bluez.Device1", "Icon")?;
+    /// Like [`Opt::load_config`], but with the configuration file at `config_path` and the
+    /// default devices file at `devices_path`, neither of which has to exist.
+    fn load_config_from(
+        &mut self,
+        config_path: Option<&std::path::Path>,
+        devices_path: Option<&std::path::Path>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // Read everything before changing anything, so that a mistake in one file leaves the
+        // configuration from before in place.
+        let config = match config_path {
+            Some(path) => Config::load_from(path)?,
+            None => Config::default(),
+        };
+        let icon_map = match &self.icon_map {
+            Some(path) => Some(IconMap::load_from(path)?),
+            None => None,
+        };
+        // Unlike the default list, one named by --devices-file has to exist.
+        let list = match (&self.devices_file, devices_path) {
+            (Some(path), _) => DeviceList::load_from(path)?,
+            (None, Some(path)) => DeviceList::load_or_default(path)?,
+            (None, None) => DeviceList::default(),
+        };
 
-            device_list.push(Device {
-                name,
-                icon: Icon(icon),
-                power: power.into(),
-            });
+        self.style.set_icons(config.icons);
+        self.aliases.clone_from(&self.cli_aliases);
+        for (address, alias) in config.aliases {
+            self.aliases.entry(address).or_insert(alias);
+        }
+        self.order = config.order;
+        self.formats = config.formats;
+        self.style.set_icon_thresholds(config.thresholds);
+        if let Some(icon_map) = icon_map {
+            self.style.set_icon_map(icon_map.glyphs);
         }
 
-        device_list
-    };
+        // Devices given on the command line (or picked by --name) replace the list, but its
+        // aliases still apply.
+        if self.addresses_from_file
+            || (!self.lists_devices() && self.partial_addresses.is_empty() && self.names.is_empty())
+        {
+            self.addresses = list.addresses;
+            self.addresses_from_file = true;
+        }
+        for (address, alias) in list.aliases {
+            self.aliases.entry(address).or_insert(alias);
+        }
+
+        Ok(())
+    }
+
+    /// Add a full address, or the end of one to be resolved by [`Opt::resolve_addresses`].
+    fn add_address(&mut self, arg: &str) -> Result<(), OptError> {
+        if is_valid_address(arg) {
+            self.addresses.push(arg.to_string());
+        } else if is_partial_address(arg) {
+            self.partial_addresses.push(arg.to_string());
+        } else {
+            return Err(OptError::Invalid(format!(
+                "invalid bluetooth address: '{arg}' (expected e.g. AA:BB:CC:DD:EE:FF, or the end of one like DD:EE:FF)"
+            )));
+        }
+        Ok(())
+    }
+
+    /// Replace the partial addresses with the full address of the one device each ends.
+    fn resolve_addresses(
+        &mut self,
+        source: &impl BatterySource,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        debug!("listing devices to resolve partial addresses");
+        let objects = source.managed_objects()?;
+        for partial in std::mem::take(&mut self.partial_addresses) {
+            let address = resolve_address(&objects, self.adapter(), &partial)?;
+            debug!("resolved {partial} to {address}");
+            self.addresses.push(address);
+        }
+        Ok(())
+    }
+
+    fn adapter(&self) -> &str {
+        self.adapter.as_deref().unwrap_or("hci0")
+    }
+
+    fn fmt(&self) -> DeviceFormat {
+        self.fmt.unwrap_or_default()
+    }
+
+    /// The format to show `device` in, from its icon if the configuration has one for it.
+    fn fmt_for(&self, device: &Device) -> DeviceFormat {
+        self.formats
+            .get(&device.icon.0)
+            .copied()
+            .unwrap_or_else(|| self.fmt())
+    }
+
+    fn service(&self) -> &str {
+        self.service.as_deref().unwrap_or(BLUEZ_SERVICE)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout.unwrap_or(Duration::from_secs(5))
+    }
+
+    fn cache_ttl(&self) -> Duration {
+        self.cache_ttl.unwrap_or(Duration::from_secs(300))
+    }
+
+    /// Whether any devices were given on the command line, by address or object path.
+    fn lists_devices(&self) -> bool {
+        !self.addresses.is_empty() || !self.paths.is_empty()
+    }
+
+    /// The object paths of the devices given on the command line.
+    fn device_paths(&self) -> Vec<String> {
+        self.addresses
+            .iter()
+            .map(|address| device_path(self.adapter(), address))
+            .chain(self.paths.iter().cloned())
+            .collect()
+    }
+
+    /// Where the device with this address is among the devices given on the command line.
+    fn arg_position(&self, address: &str) -> Option<usize> {
+        let suffix = format!("/dev_{}", address.replace(':', "_")).to_ascii_uppercase();
+        self.device_paths()
+            .iter()
+            .position(|x| x.to_ascii_uppercase().ends_with(&suffix))
+    }
+
+    /// Whether the device with this address was given on the command line, by address or object
+    /// path.
+    fn lists_address(&self, address: &str) -> bool {
+        let suffix = format!("/dev_{}", address.replace(':', "_"));
+        self.addresses
+            .iter()
+            .any(|x| x.eq_ignore_ascii_case(address))
+            || self.paths.iter().any(|x| {
+                x.to_ascii_uppercase()
+                    .ends_with(&suffix.to_ascii_uppercase())
+            })
+    }
+
+    /// Whether the device with this address should be shown, based on the devices given on the
+    /// command line.
+    fn wants_address(&self, address: &str) -> bool {
+        !self.lists_devices() || !self.names.is_empty() || self.lists_address(address)
+    }
+
+    /// Whether `pattern`, given with `--name`, is part of the device's name or alias, ignoring
+    /// case.
+    fn name_matches(&self, device: &Device, pattern: &str) -> bool {
+        let pattern = pattern.to_lowercase();
+        std::iter::once(&device.name)
+            .chain(self.aliases.get(&device.address))
+            .any(|x| x.to_lowercase().contains(&pattern))
+    }
+}
+
+/// Whether `arg` is several single-character flags combined, such as `-3l`.
+fn is_short_flag_cluster(arg: &str) -> bool {
+    arg.strip_prefix('-')
+        .is_some_and(|x| x.len() > 1 && x.chars().all(|c| SHORT_FLAGS.contains(c)))
+}
+
+/// Whether `arg` is the last one to five bytes of an address, e.g. `DD:EE:FF`.
+fn is_partial_address(arg: &str) -> bool {
+    let groups = arg.split(':').collect::<Vec<_>>();
+    groups.len() < 6
+        && groups
+            .iter()
+            .all(|x| x.len() == 2 && x.chars().all(|c| c.is_ascii_hexdigit()))
+}
 
-    devices.sort_unstable();
+fn required_value(flag: &str, args: &mut impl Iterator<Item = String>) -> Result<String, OptError> {
+    args.next()
+        .ok_or_else(|| OptError::MissingValue(flag.to_string()))
+}
 
-    for (i, device) in devices.iter().enumerate() {
-        print!(
-            "{}",
-            match opt.fmt {
-                DeviceFormat::Long => device.long(opt.i3),
-                DeviceFormat::Short => device.short(),
-                DeviceFormat::Narrow => device.narrow(opt.i3),
+/// Expand `\n`, `\t` and `\\` escapes, so that they can be passed without the shell's help.
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => out.push('\n'),
+            Some('t') => out.push('\t'),
+            Some('\\') => out.push('\\'),
+            Some(c) => {
+                out.push('\\');
+                out.push(c);
             }
-        );
+            None => out.push('\\'),
+        }
+    }
+    out
+}
 
-        if i < devices.len() - 1 {
-            if let DeviceFormat::Short = opt.fmt {
-                print!("  ");
-            } else {
-                print!(" ");
+fn parse_value<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, OptError>
+where
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| OptError::Invalid(format!("invalid value for {flag}: '{value}': {e}")))
+}
+
+/// Why [`Opt::from_args`] didn't return options to run with.
+#[derive(Debug)]
+enum OptError {
+    /// `--help` was given.
+    Help,
+    /// `-h` or `--usage` was given.
+    Usage,
+    /// `-V` or `--version` was given.
+    Version,
+    /// The flag was given without its value.
+    MissingValue(String),
+    /// The argument isn't a known flag.
+    Unknown,
+    /// An argument couldn't be used, for the reason in the message.
+    Invalid(String),
+}
+
+impl OptError {
+    /// Print the message (or the help the user asked for), and exit with the appropriate status.
+    fn exit(self) -> ! {
+        match &self {
+            Self::Help => println!("{USAGE_MESSAGE}\n\n{HELP_MESSAGE_FRAGMENT}"),
+            Self::Usage => println!("{USAGE_MESSAGE}"),
+            Self::Version => println!("{} {}", env!("CARGO_BIN_NAME"), env!("CARGO_PKG_VERSION")),
+            Self::MissingValue(flag) => {
+                eprintln!("{}: {flag} requires a value", env!("CARGO_BIN_NAME"));
+                eprintln!("{USAGE_MESSAGE}");
             }
+            Self::Unknown => eprintln!("{USAGE_MESSAGE}"),
+            Self::Invalid(message) => eprintln!("{}: {message}", env!("CARGO_BIN_NAME")),
+        }
+
+        let status = match self {
+            Self::Help | Self::Usage | Self::Version => 0,
+            _ => 1,
+        };
+        std::process::exit(status);
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let result = run();
+    if let Err(e) = &result
+        && e.downcast_ref().is_some_and(is_service_unknown)
+    {
+        // Not a warning, but still silenced by --quiet: the exit status says it all.
+        log!(1, "bluetooth service not running");
+        std::process::exit(4);
+    }
+    result
+}
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
+    let mut opt = match Opt::from_args(std::env::args().skip(1)) {
+        Ok(opt) => opt,
+        Err(e) => e.exit(),
+    };
+    if opt.stdin
+        && let Err(e) = opt.read_addresses(std::io::stdin().lock())
+    {
+        e.exit();
+    }
+    if opt.fmt.is_none()
+        && let Some(value) = std::env::var_os("BLUETOOTH_BATTERY_FORMAT").filter(|x| !x.is_empty())
+    {
+        match parse_value("BLUETOOTH_BATTERY_FORMAT", &value.to_string_lossy()) {
+            Ok(fmt) => opt.fmt = Some(fmt),
+            Err(e) => e.exit(),
         }
+    }
+    bluetooth_battery::log::LEVEL.store(
+        if opt.quiet { 0 } else { 1 + opt.verbose },
+        Ordering::Relaxed,
+    );
+    opt.style.no_color = std::env::var_os("NO_COLOR").is_some_and(|x| !x.is_empty());
+    // In the order setlocale(3) looks at them for LC_NUMERIC.
+    let locale = opt.locale.clone().or_else(|| {
+        ["LC_ALL", "LC_NUMERIC", "LANG"]
+            .iter()
+            .find_map(|x| std::env::var(x).ok().filter(|x| !x.is_empty()))
+    });
+    if let Some(locale) = locale {
+        opt.style.numbers = NumberFormat::for_locale(&locale);
+    }
+
+    // Before the devices file can fill in devices that weren't asked about.
+    if opt.explain && !opt.lists_devices() && opt.partial_addresses.is_empty() {
+        OptError::Invalid("--explain needs a DEVICE".to_string()).exit();
+    }
+    opt.cli_aliases = opt.aliases.clone();
+    opt.load_config()?;
+
+    if opt.list_icons {
+        list_icons(&opt.style);
+        return Ok(());
+    }
+
+    if opt.dry_run {
+        dry_run(&opt);
+        return Ok(());
+    }
+
+    let (bus, connect): (_, fn() -> _) = if opt.session_bus {
+        ("session", Connection::new_session)
+    } else {
+        ("system", Connection::new_system)
+    };
+    let conn = retry(opt.retries, &format!("connect to the {bus} bus"), connect)?;
+    let bluez = BlueZ::new(&conn, opt.timeout()).with_service(opt.service());
+    if opt.check {
+        return check(&bluez);
+    }
+    if !opt.partial_addresses.is_empty() {
+        opt.resolve_addresses(&bluez)?;
+    }
+    if opt.explain {
+        return explain(&bluez, &opt);
+    }
+
+    let mut notifier = opt
+        .notify
+        .map(|x| Notifier::new(x, opt.timeout(), opt.notify_interval));
+
+    if opt.monitor {
+        return monitor(&conn, &bluez, &mut opt, &mut notifier);
+    }
+
+    if let Some(interval) = opt.watch {
+        return watch(&bluez, &opt, interval, &mut notifier);
+    }
+
+    let devices = retry(opt.retries, "read devices", || fetch_devices(&bluez, &opt))?;
+    if opt.require_device && devices.is_empty() {
+        warn!("no devices found");
+        std::process::exit(3);
+    }
+
+    if matches!(opt.output, OutputMode::Raw) && devices.len() > 1 {
+        eprintln!(
+            "{}: --raw requires a single device, but found {}",
+            env!("CARGO_BIN_NAME"),
+            devices.len()
+        );
+        std::process::exit(1);
+    }
+
+    if let Some(notifier) = &mut notifier {
+        notifier.check(&devices);
+    }
 
-        if i == devices.len() - 1 {
-            println!();
+    let output = render(&devices, &opt);
+    // An empty file still says something, unlike an empty line.
+    if !output.is_empty() || opt.output_file.is_some() {
+        write_output(&output, &opt)?;
+    }
+
+    if let Some(threshold) = opt.fail_below
+        && devices
+            .iter()
+            .any(|d| d.power.is_some_and(|x| x < threshold))
+    {
+        std::process::exit(2);
+    }
+
+    if opt.status_exit {
+        let worst = devices
+            .iter()
+            .filter_map(|d| Some(opt.style.thresholds_for(&d.icon).level(d.power?)))
+            .max();
+        match worst {
+            Some(Level::Critical) => std::process::exit(2),
+            Some(Level::Warning) => std::process::exit(1),
+            Some(Level::Normal) | None => {}
         }
     }
 
     Ok(())
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
-struct Device {
-    name: String,
-    icon: Icon,
-    power: u64,
+/// Set by the signal handler installed by [`install_interrupt_handler`] to ask a long-running
+/// loop to stop.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
 }
 
-impl Device {
-    fn long(&self, i3: bool) -> String {
-        format!(
-            "{}{} ({}%)",
-            if i3 {
-                self.icon.material_symbols()
-            } else {
-                self.icon.emoji()
-            }
-            .unwrap_or_default(),
-            self.name,
-            self.power
-        )
+/// Set by the signal handler installed by [`install_reload_handler`] to ask `--monitor` to read
+/// the configuration again.
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle_reload(_: libc::c_int) {
+    RELOAD.store(true, Ordering::SeqCst);
+}
+
+fn install_reload_handler() {
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe.
+    unsafe {
+        libc::signal(
+            libc::SIGHUP,
+            handle_reload as *const () as libc::sighandler_t,
+        );
     }
+}
 
-    fn short(&self) -> String {
-        format!("{} {}%", self.name, self.power)
+fn install_interrupt_handler() {
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe.
+    unsafe {
+        libc::signal(
+            libc::SIGINT,
+            handle_interrupt as *const () as libc::sighandler_t,
+        );
+        libc::signal(
+            libc::SIGTERM,
+            handle_interrupt as *const () as libc::sighandler_t,
+        );
     }
+}
 
-    fn narrow(&self, i3: bool) -> String {
-        format!(
-            "{}{}%",
-            if i3 {
-                self.icon.material_symbols()
-            } else {
-                self.icon.emoji()
-            }
-            .unwrap_or_default(),
-            self.power
-        )
+/// Print a fresh line every `interval` until interrupted with Ctrl-C.
+fn watch(
+    source: &impl BatterySource,
+    opt: &Opt,
+    interval: Duration,
+    notifier: &mut Option<Notifier>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    install_interrupt_handler();
+
+    let mut hysteresis = opt.hysteresis.map(Hysteresis::new);
+    let mut trends = opt.trend.then(Trends::default);
+    let mut last = None;
+    // Like the connection, only the first query is retried, while BlueZ may still be starting.
+    let mut retries = opt.retries;
+    while !INTERRUPTED.load(Ordering::SeqCst) {
+        let mut devices = retry(std::mem::take(&mut retries), "read devices", || {
+            fetch_devices(source, opt)
+        })?;
+        if let Some(hysteresis) = &mut hysteresis {
+            hysteresis.apply(&mut devices, &opt.style);
+        }
+        if let Some(trends) = &mut trends {
+            trends.apply(&mut devices);
+        }
+        if let Some(notifier) = notifier {
+            notifier.check(&devices);
+        }
+        let output = render(&devices, opt);
+        if !opt.on_change_only || last.as_ref() != Some(&output) {
+            write_output(&output, opt)?;
+            last = Some(output);
+        }
+
+        let deadline = Instant::now() + interval;
+        while !INTERRUPTED.load(Ordering::SeqCst) {
+            let Some(remaining) = deadline.checked_duration_since(Instant::now()) else {
+                break;
+            };
+            std::thread::sleep(remaining.min(Duration::from_millis(100)));
+        }
     }
+
+    Ok(())
 }
 
-#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
-struct Icon(String);
+/// The level each device was last shown at, so that `--hysteresis` can hold it there.
+struct Hysteresis {
+    margin: u64,
+    levels: HashMap<String, Level>,
+}
 
-impl std::str::FromStr for Icon {
-    type Err = ();
+impl Hysteresis {
+    fn new(margin: u64) -> Self {
+        Self {
+            margin,
+            levels: HashMap::new(),
+        }
+    }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Self(s.to_string()))
-    }
-}
-
-impl Icon {
-    // https://specifications.freedesktop.org/icon-naming-spec/latest/#devices
-    fn emoji(&self) -> Option<&str> {
-        match self.0.as_str() {
-            "audio-headset" => Some("🎧 "),
-            "phone" | "pda" => Some("📱 "),
-            "input-keyboard" => Some("⌨️ "),
-            "input-mouse" => Some("🖱️ "),
-            "input-gaming" => Some("🎮 "),
-            "input-tablet" => Some("🖍️  "),
-            "multimedia-player" => Some("📻 "),
-            "printer" | "scanner" => Some("🖨️  "),
-            _ => None,
-        }
-    }
-
-    fn material_symbols(&self) -> Option<&str> {
-        // https://docs.gtk.org/Pango/pango_markup.html#the-span-attributes
-        macro_rules! i3 {
-            ($x:literal) => {
-                concat!(
-                    "<span font_desc='Material Symbols Outlined @opsz=20,FILL=1,GRAD=-25' rise='-3pt'>",
-                    $x,
-                    "</span> "
-                )
+    fn apply(&mut self, devices: &mut [Device], style: &Style) {
+        for device in devices {
+            let Some(power) = device.power else {
+                continue;
             };
+            let previous = self.levels.get(&device.address).copied();
+            let level =
+                style
+                    .thresholds_for(&device.icon)
+                    .level_after(power, previous, self.margin);
+            self.levels.insert(device.address.clone(), level);
+            device.level = Some(level);
         }
+    }
+}
+
+/// Each device's last battery level and which way it went to get there, for `--trend`.
+#[derive(Default)]
+struct Trends {
+    levels: HashMap<String, (u64, Trend)>,
+}
 
-        // https://specifications.freedesktop.org/icon-naming-spec/latest/#devices
-        match self.0.as_str() {
-            "audio-headset" => Some(i3!("headphones")),
-            "phone" | "pda" => Some(i3!("smartphone")),
-            "input-keyboard" => Some(i3!("keyboard")),
-            "input-mouse" => Some(i3!("mouse")),
-            "input-gaming" => Some(i3!("sports_esports")),
-            "input-tablet" => Some(i3!("tablet_android")),
-            "multimedia-player" => Some(i3!("media_bluetooth_on")),
-            "printer" => Some(i3!("print")),
-            "scanner" => Some(i3!("scanner")),
-            _ => None,
+impl Trends {
+    fn apply(&mut self, devices: &mut [Device]) {
+        for device in devices {
+            let Some(power) = device.power else {
+                continue;
+            };
+            let trend = match self.levels.get(&device.address) {
+                Some(&(previous, _)) if power > previous => Trend::Rising,
+                Some(&(previous, _)) if power < previous => Trend::Falling,
+                // Unchanged since the last update, so still going the same way.
+                Some(&(_, trend)) => trend,
+                None => Trend::Steady,
+            };
+            self.levels.insert(device.address.clone(), (power, trend));
+            device.trend = Some(trend);
         }
     }
 }
 
+/// The battery levels and charging states last read from UPower, for `--monitor`.
+///
+/// UPower isn't watched for changes, so it's only asked again after a change in BlueZ, with
+/// `--max-stale`, or once [`UPowerLevels::MAX_AGE`] has passed.
 #[derive(Default)]
-enum DeviceFormat {
-    Long,
-    Short,
-    #[default]
-    Narrow,
+struct UPowerLevels {
+    levels: HashMap<String, UPowerBattery>,
+    read_at: Option<Instant>,
+    /// Whether failing to read them has been warned about, so that it's only warned about once.
+    warned: bool,
+}
+
+impl UPowerLevels {
+    const MAX_AGE: Duration = Duration::from_secs(60);
+
+    /// Ask UPower again the next time they're applied.
+    fn invalidate(&mut self) {
+        self.read_at = None;
+    }
+
+    fn apply(&mut self, source: &impl BatterySource, devices: &mut [Device], opt: &Opt) {
+        if !wants_upower(devices, opt) {
+            return;
+        }
+
+        if self.read_at.is_none_or(|x| x.elapsed() >= Self::MAX_AGE) {
+            debug!("asking org.freedesktop.UPower for battery levels and states");
+            self.read_at = Some(Instant::now());
+            match source.upower_levels() {
+                Ok(levels) => self.levels = levels,
+                Err(e) => {
+                    let level = if self.warned { 2 } else { 1 };
+                    log!(
+                        level,
+                        "failed to read battery levels from UPower: {}",
+                        e.message().unwrap_or("unknown error")
+                    );
+                    self.warned = true;
+                    self.levels.clear();
+                }
+            }
+        }
+        apply_upower(devices, &self.levels, opt);
+    }
+}
+
+/// Print `ok` if BlueZ answers, for `--check`. Otherwise print why not and exit with status 1,
+/// or 4 if it isn't running.
+fn check(source: &impl BatterySource) -> Result<(), Box<dyn std::error::Error>> {
+    debug!("checking that org.bluez answers");
+    match source.managed_objects() {
+        Ok(_) => {
+            println!("ok");
+            Ok(())
+        }
+        Err(e) if is_service_unknown(&e) => Err(e.into()),
+        Err(e) => {
+            eprintln!(
+                "{}: failed to list devices: {}",
+                env!("CARGO_BIN_NAME"),
+                e.message().unwrap_or("unknown error")
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Call `f` until it succeeds or has been retried `retries` times, doubling the delay between
+/// attempts from half a second.
+///
+/// This rides out the system bus or BlueZ not being ready yet, e.g. just after resuming from
+/// suspend.
+fn retry<T, E: std::fmt::Display>(
+    retries: u32,
+    what: &str,
+    mut f: impl FnMut() -> Result<T, E>,
+) -> Result<T, E> {
+    let mut delay = Duration::from_millis(500);
+    for _ in 0..retries {
+        match f() {
+            Ok(x) => return Ok(x),
+            Err(e) => {
+                warn!("failed to {what}, retrying in {}ms: {e}", delay.as_millis());
+                std::thread::sleep(delay);
+                delay *= 2;
+            }
+        }
+    }
+    f()
+}
+
+/// Print the properties read for each device given on the command line, with their types, and
+/// the device built from them, for `--explain`.
+fn explain(source: &impl BatterySource, opt: &Opt) -> Result<(), Box<dyn std::error::Error>> {
+    for path in opt.device_paths() {
+        println!("{path}");
+        for (interface, names) in [
+            (
+                "org.bluez.Device1",
+                &["Address", "Connected", "Name", "Alias", "Icon", "RSSI"][..],
+            ),
+            ("org.bluez.Battery1", &["Percentage", "Source"][..]),
+        ] {
+            let props = match source.get_all(&path, interface) {
+                Ok(x) => x,
+                Err(e) if is_service_unknown(&e) => return Err(e.into()),
+                Err(e) => {
+                    println!("  {interface}: {}", e.message().unwrap_or("unknown error"));
+                    continue;
+                }
+            };
+            for name in names {
+                match props.get(*name) {
+                    Some(value) => println!(
+                        "  {interface}.{name}: {} {:?}",
+                        value.0.signature(),
+                        value.0
+                    ),
+                    None => println!("  {interface}.{name}: missing"),
+                }
+            }
+        }
+
+        match fetch_device(source, &path) {
+            Ok(device) => {
+                println!("  device: {device:?}");
+                // With the aliases, filters and rounding that a normal run would apply.
+                let mut devices = vec![device];
+                process_devices(&mut devices, opt);
+                if devices.is_empty() {
+                    println!("  shown as: nothing, it's filtered out");
+                } else {
+                    println!("  shown as: {}", render(&devices, opt));
+                }
+            }
+            Err(e) => println!("  device: none, {}", e.message().unwrap_or("unknown error")),
+        }
+    }
+    Ok(())
+}
+
+/// Print the glyphs for each built-in icon, for `--list-icons`.
+fn list_icons(style: &Style) {
+    let builtin = BUILTIN_ICONS.iter().map(|x| x.name);
+    let mapped = style
+        .mapped_icons()
+        .into_iter()
+        .filter(|x| BUILTIN_ICONS.iter().all(|builtin| builtin.name != *x));
+    for name in builtin.chain(mapped) {
+        let icon = Icon(name.to_string());
+        println!(
+            "{name}\t{}\t{}",
+            style.emoji(&icon).trim_end(),
+            style.material_symbols_name(&icon).unwrap_or_default()
+        );
+    }
+}
+
+/// Print what would be asked of BlueZ, without connecting to it.
+fn dry_run(opt: &Opt) {
+    // Names can only be matched against every device, as in fetch_devices.
+    if (!opt.lists_devices() && opt.partial_addresses.is_empty()) || !opt.names.is_empty() {
+        println!("org.freedesktop.DBus.ObjectManager.GetManagedObjects on /");
+        return;
+    }
+
+    match &opt.adapter {
+        _ if opt.addresses.is_empty() && opt.partial_addresses.is_empty() => {}
+        Some(adapter) => {
+            println!("adapter: {adapter}");
+            println!(
+                "org.freedesktop.DBus.ObjectManager.GetManagedObjects on / (to check {adapter} exists)"
+            );
+        }
+        None => println!(
+            "adapter: {} (assumed, use --adapter to change)",
+            opt.adapter()
+        ),
+    }
+    if !opt.partial_addresses.is_empty() {
+        println!(
+            "org.freedesktop.DBus.ObjectManager.GetManagedObjects on / (to find the addresses ending in {})",
+            opt.partial_addresses.join(", ").to_ascii_uppercase()
+        );
+    }
+    // The paths of the devices found by the end of their address aren't known yet.
+    let partial_paths = opt
+        .partial_addresses
+        .iter()
+        .map(|x| device_path(opt.adapter(), &format!("*:{x}")));
+    for path in opt.device_paths().into_iter().chain(partial_paths) {
+        println!("{path}");
+        for call in [
+            "org.freedesktop.DBus.Properties.GetAll org.bluez.Device1 (Address, Connected, Name, Icon and RSSI if there is one)",
+            "org.freedesktop.DBus.Properties.GetAll org.bluez.Battery1 (Percentage and Source if there is one, if connected)",
+        ] {
+            println!("  {call}");
+        }
+    }
+}
+
+fn fetch_devices(
+    source: &impl BatterySource,
+    opt: &Opt,
+) -> Result<Vec<Device>, Box<dyn std::error::Error>> {
+    // Names can only be matched against every device, and devices without a battery in BlueZ
+    // can't be read one at a time.
+    let mut devices = if !opt.lists_devices() || !opt.names.is_empty() || opt.upower_fallback {
+        debug!("listing all devices known to {}", opt.service());
+        let mut devices = match source.managed_objects() {
+            Ok(objects) => {
+                if let Some(adapter) = &opt.adapter {
+                    check_adapter(&objects, adapter)?;
+                }
+                devices_from_objects(&objects, opt.adapter.as_deref())
+            }
+            // One slow device can hold up the whole list, so show the ones that do answer.
+            Err(e) if is_timeout(&e) => {
+                warn!("timed out listing devices, reading them one at a time instead");
+                let (devices, failures) = fetch_devices_one_by_one(source, opt.adapter.as_deref())?;
+                for (path, e) in failures {
+                    warn!(
+                        "skipping {path}: {}",
+                        e.message().unwrap_or("unknown error")
+                    );
+                }
+                devices
+            }
+            Err(e) => return Err(e.into()),
+        };
+        debug!("found {} devices", devices.len());
+        devices.retain(|d| opt.wants_address(&d.address));
+        devices
+    } else {
+        if let Some(adapter) = &opt.adapter {
+            debug!("checking that adapter {adapter} exists");
+            check_adapter(&source.managed_objects()?, adapter)?;
+        }
+
+        let mut device_list = vec![];
+        for path in opt.device_paths() {
+            debug!("reading {path}");
+            // One device without a battery (or that BlueZ doesn't know about) shouldn't hide the
+            // others.
+            match fetch_device(source, &path) {
+                Ok(device) => device_list.push(device),
+                Err(e) if is_service_unknown(&e) => return Err(e.into()),
+                Err(e) => warn!(
+                    "skipping {path}: {}",
+                    e.message().unwrap_or("unknown error")
+                ),
+            }
+        }
+
+        device_list
+    };
+
+    merge_upower(source, &mut devices, opt);
+
+    if let Some(path) = &opt.cache {
+        let mut cache = Cache::load(path, opt.cache_ttl())?;
+        cache.update(&devices);
+        cache.save()?;
+        cache.fill(&mut devices, |address| opt.wants_address(address));
+    }
+
+    for pattern in &opt.names {
+        if !devices.iter().any(|d| opt.name_matches(d, pattern)) {
+            warn!("no device matches --name {pattern}");
+        }
+    }
+
+    process_devices(&mut devices, opt);
+
+    Ok(devices)
+}
+
+/// Whether UPower has to be asked about `devices`: for the battery levels of connected devices
+/// that BlueZ has none for (with `--upower-fallback`), or for charging states (with
+/// `--show-charging`).
+fn wants_upower(devices: &[Device], opt: &Opt) -> bool {
+    opt.style.show_charging
+        || (opt.upower_fallback && devices.iter().any(|d| d.connected && d.power.is_none()))
+}
+
+/// Fill in the battery levels and charging states of connected devices from UPower's `levels`.
+fn apply_upower(devices: &mut [Device], levels: &HashMap<String, UPowerBattery>, opt: &Opt) {
+    for device in devices.iter_mut().filter(|d| d.connected) {
+        let battery = levels.get(&device.address);
+        if opt.upower_fallback && device.power.is_none() {
+            device.power = battery.and_then(|x| x.power);
+        }
+        device.charging = battery.map(|x| x.charging);
+    }
+}
+
+/// Fill in what's wanted from UPower, as described by [`wants_upower`].
+fn merge_upower(source: &impl BatterySource, devices: &mut [Device], opt: &Opt) {
+    if !wants_upower(devices, opt) {
+        return;
+    }
+
+    debug!("asking org.freedesktop.UPower for battery levels and states");
+    match source.upower_levels() {
+        Ok(levels) => apply_upower(devices, &levels, opt),
+        // Only a fallback, so BlueZ's devices are still worth showing.
+        Err(e) => warn!(
+            "failed to read battery levels from UPower: {}",
+            e.message().unwrap_or("unknown error")
+        ),
+    }
+}
+
+/// Apply the aliases, filters and sort order requested by the user.
+fn process_devices(devices: &mut Vec<Device>, opt: &Opt) {
+    if !opt.names.is_empty() {
+        devices.retain(|d| {
+            opt.lists_address(&d.address) || opt.names.iter().any(|x| opt.name_matches(d, x))
+        });
+    }
+
+    for device in devices.iter_mut() {
+        if let Some(alias) = opt.aliases.get(&device.address) {
+            device.name.clone_from(alias);
+        }
+        if opt.clamp {
+            device.clamp_power();
+        }
+        if let Some(step) = opt.round {
+            device.round(step);
+        }
+    }
+
+    filter_devices(devices, opt);
+    if let Some(how) = opt.merge_pairs {
+        *devices = merge_pairs(std::mem::take(devices), how);
+    }
+    sort_devices(devices, opt);
+
+    if opt.lowest {
+        // The first of the lowest in sort order, so that ties are settled by --sort.
+        let lowest = devices
+            .iter()
+            .enumerate()
+            .filter(|(_, d)| d.power.is_some())
+            .min_by_key(|(_, d)| d.power)
+            .map(|(i, _)| i);
+        match lowest {
+            Some(i) => {
+                let device = devices.swap_remove(i);
+                *devices = vec![device];
+            }
+            None => devices.clear(),
+        }
+    }
+
+    if let Some(limit) = opt.limit {
+        devices.truncate(limit);
+    }
+
+    if opt.strict_icons {
+        let mut unknown = devices
+            .iter()
+            .map(|d| &d.icon)
+            .filter(|x| !opt.style.knows_icon(x))
+            .map(|x| x.0.as_str())
+            .collect::<Vec<_>>();
+        unknown.sort_unstable();
+        unknown.dedup();
+        for icon in unknown {
+            warn!("no emoji or glyph for icon {icon:?}");
+        }
+    }
+}
+
+fn filter_devices(devices: &mut Vec<Device>, opt: &Opt) {
+    for d in devices.iter() {
+        trace!(
+            "{} ({}): icon {}, connected: {}, battery: {}",
+            d.address,
+            d.name,
+            d.icon.0,
+            d.connected,
+            d.power.map_or("unknown".to_string(), |x| format!("{x}%"))
+        );
+    }
+
+    devices.retain_mut(|d| {
+        if d.connected || d.age.is_some() {
+            if d.power.is_none() {
+                debug!("hiding {} ({}): no battery level", d.name, d.address);
+            }
+            return d.power.is_some();
+        }
+        if opt.all && d.power.is_some() {
+            return true;
+        }
+        // BlueZ can keep the battery level from before the device disconnected, which is only
+        // wanted with --all.
+        d.power = None;
+        if !opt.show_disconnected {
+            debug!("hiding {} ({}): not connected", d.name, d.address);
+        }
+        opt.show_disconnected
+    });
+
+    if let Some(threshold) = opt.only_below {
+        devices.retain(|d| {
+            let keep = d.power.is_some_and(|x| x < threshold);
+            if !keep {
+                debug!("hiding {} ({}): not below {threshold}%", d.name, d.address);
+            }
+            keep
+        });
+    }
+}
+
+fn sort_devices(devices: &mut [Device], opt: &Opt) {
+    match opt.sort {
+        None => devices.sort_unstable(),
+        Some(SortKey::Name) => devices.sort_unstable_by(|a, b| a.name.cmp(&b.name).then(a.cmp(b))),
+        Some(SortKey::Power) => {
+            devices.sort_unstable_by(|a, b| a.power.cmp(&b.power).then(a.cmp(b)));
+        }
+        Some(SortKey::Icon) => devices.sort_unstable_by(|a, b| a.icon.cmp(&b.icon).then(a.cmp(b))),
+    }
+
+    if opt.preserve_arg_order {
+        // Also a stable sort, so that devices from --name are still sorted after the others.
+        devices.sort_by_key(|d| opt.arg_position(&d.address).unwrap_or(usize::MAX));
+    }
+
+    if opt.reverse {
+        devices.reverse();
+    }
+
+    if opt.pin_order {
+        // A stable sort, so devices that aren't pinned keep the order from above.
+        devices.sort_by_key(|d| {
+            opt.order
+                .iter()
+                .position(|x| *x == d.address)
+                .unwrap_or(usize::MAX)
+        });
+    }
+}
+
+/// A change to the objects exported by BlueZ, as reported by its signals.
+enum Event {
+    PropertiesChanged(Path<'static>, PropertiesPropertiesChanged),
+    InterfacesAdded(ObjectManagerInterfacesAdded),
+    InterfacesRemoved(ObjectManagerInterfacesRemoved),
+}
+
+impl std::fmt::Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::PropertiesChanged(path, signal) => {
+                write!(
+                    f,
+                    "properties of {} changed on {path}",
+                    signal.interface_name
+                )
+            }
+            Self::InterfacesAdded(signal) => write!(f, "interfaces added to {}", signal.object),
+            Self::InterfacesRemoved(signal) => {
+                write!(f, "interfaces removed from {}", signal.object)
+            }
+        }
+    }
+}
+
+impl Event {
+    fn apply(self, objects: &mut ManagedObjects) {
+        match self {
+            Self::PropertiesChanged(path, signal) => {
+                let Some(props) = objects
+                    .get_mut(&path)
+                    .and_then(|x| x.get_mut(&signal.interface_name))
+                else {
+                    return;
+                };
+                props.extend(signal.changed_properties);
+                for name in signal.invalidated_properties {
+                    props.remove(&name);
+                }
+            }
+            Self::InterfacesAdded(signal) => {
+                objects
+                    .entry(signal.object)
+                    .or_default()
+                    .extend(signal.interfaces);
+            }
+            Self::InterfacesRemoved(signal) => {
+                if let Some(interfaces) = objects.get_mut(&signal.object) {
+                    for name in &signal.interfaces {
+                        interfaces.remove(name);
+                    }
+                    if interfaces.is_empty() {
+                        objects.remove(&signal.object);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Print a new line whenever BlueZ reports a change that affects the output, until interrupted
+/// with Ctrl-C.
+fn monitor(
+    conn: &Connection,
+    source: &impl BatterySource,
+    opt: &mut Opt,
+    notifier: &mut Option<Notifier>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    install_interrupt_handler();
+    install_reload_handler();
+
+    let (tx, rx) = mpsc::channel();
+    let sender = BusName::from(opt.service());
+
+    let events = tx.clone();
+    conn.add_match(
+        PropertiesPropertiesChanged::match_rule(Some(&sender), None).static_clone(),
+        move |signal: PropertiesPropertiesChanged, _: &Connection, msg: &Message| {
+            if let Some(path) = msg.path() {
+                let _ = events.send(Event::PropertiesChanged(path.into_static(), signal));
+            }
+            true
+        },
+    )?;
+    let events = tx.clone();
+    conn.add_match(
+        ObjectManagerInterfacesAdded::match_rule(Some(&sender), None).static_clone(),
+        move |signal, _: &Connection, _: &Message| {
+            let _ = events.send(Event::InterfacesAdded(signal));
+            true
+        },
+    )?;
+    let events = tx;
+    conn.add_match(
+        ObjectManagerInterfacesRemoved::match_rule(Some(&sender), None).static_clone(),
+        move |signal, _: &Connection, _: &Message| {
+            let _ = events.send(Event::InterfacesRemoved(signal));
+            true
+        },
+    )?;
+
+    // Subscribe before taking the snapshot so that no change can slip in between.
+    debug!("subscribed to org.bluez signals, listing all devices");
+    let mut objects = retry(opt.retries, "list devices", || source.managed_objects())?;
+
+    if let Some(adapter) = &opt.adapter {
+        check_adapter(&objects, adapter)?;
+    }
+
+    // With --name, every device is a candidate, and they're narrowed down by process_devices.
+    let watched_paths = |opt: &Opt| {
+        if opt.names.is_empty() {
+            opt.device_paths()
+        } else {
+            vec![]
+        }
+    };
+    let mut paths = watched_paths(opt);
+
+    let mut cache = match &opt.cache {
+        Some(path) => Some(Cache::load(path, opt.cache_ttl())?),
+        None => None,
+    };
+
+    let mut hysteresis = opt.hysteresis.map(Hysteresis::new);
+    let mut trends = opt.trend.then(Trends::default);
+    let mut upower = UPowerLevels::default();
+    let mut last_output = None;
+    let mut last_refresh = Instant::now();
+    while !INTERRUPTED.load(Ordering::SeqCst) {
+        if RELOAD.swap(false, Ordering::SeqCst) {
+            debug!("reloading the configuration");
+            match opt.load_config() {
+                // The devices file may list different devices now.
+                Ok(()) => {
+                    paths = watched_paths(opt);
+                    last_output = None;
+                }
+                // Keep going with the configuration from before, rather than stopping over a typo.
+                Err(e) => warn!("failed to reload the configuration: {e}"),
+            }
+        }
+
+        // Some devices stop reporting changes, so don't rely on the signals alone.
+        if let Some(max_stale) = opt.max_stale
+            && last_refresh.elapsed() >= max_stale
+        {
+            debug!(
+                "no update for {}s, listing all devices again",
+                max_stale.as_secs()
+            );
+            objects = source.managed_objects()?;
+            upower.invalidate();
+            last_refresh = Instant::now();
+            last_output = None;
+        }
+
+        let mut devices = objects
+            .iter()
+            .filter(|&(path, _)| paths.is_empty() || paths.iter().any(|p| p == &**path))
+            .filter(|&(path, _)| opt.adapter.as_ref().is_none_or(|x| is_on_adapter(path, x)))
+            .filter_map(|(_, interfaces)| device_from_interfaces(interfaces))
+            .collect::<Vec<_>>();
+        upower.apply(source, &mut devices, opt);
+
+        if let Some(cache) = &mut cache {
+            // The last-seen times are kept up to date in memory, so only write the file when
+            // something more interesting changes.
+            if cache.update(&devices) {
+                cache.save()?;
+            }
+            cache.fill(&mut devices, |address| opt.wants_address(address));
+        }
+
+        process_devices(&mut devices, opt);
+        if let Some(hysteresis) = &mut hysteresis {
+            hysteresis.apply(&mut devices, &opt.style);
+        }
+        if let Some(trends) = &mut trends {
+            trends.apply(&mut devices);
+        }
+
+        if let Some(notifier) = notifier {
+            notifier.check(&devices);
+        }
+
+        let output = render(&devices, opt);
+        if last_output.as_ref() != Some(&output) {
+            write_output(&output, opt)?;
+            last_output = Some(output);
+        }
+
+        conn.process(Duration::from_secs(1))?;
+        for event in rx.try_iter() {
+            trace!("{event}");
+            event.apply(&mut objects);
+            upower.invalidate();
+        }
+    }
+
+    if let Some(cache) = &cache {
+        cache.save()?;
+    }
+
+    Ok(())
+}
+
+/// Sends a desktop notification when a device's battery drops to or below a threshold.
+///
+/// Each device is only notified about once, until its battery rises above the threshold again.
+struct Notifier {
+    threshold: u64,
+    timeout: Duration,
+    /// How long to wait before notifying about the same device again, however often it crosses
+    /// the threshold.
+    interval: Option<Duration>,
+    /// The session bus, which is connected to the first time a notification is sent.
+    conn: Option<Connection>,
+    /// The addresses of the devices that are still low since they were last notified about.
+    notified: HashSet<String>,
+    /// When each device was last notified about, by address, for `interval`.
+    sent: HashMap<String, Instant>,
+}
+
+impl Notifier {
+    fn new(threshold: u64, timeout: Duration, interval: Option<Duration>) -> Self {
+        Self {
+            threshold,
+            timeout,
+            interval,
+            conn: None,
+            notified: HashSet::new(),
+            sent: HashMap::new(),
+        }
+    }
+
+    fn check(&mut self, devices: &[Device]) {
+        self.notified
+            .retain(|address| devices.iter().any(|d| &d.address == address));
+        // Sends from longer ago than the interval don't hold anything back any more.
+        match self.interval {
+            Some(interval) => self.sent.retain(|_, sent| sent.elapsed() < interval),
+            None => self.sent.clear(),
+        }
+
+        for device in devices {
+            if device.power.is_none_or(|x| x > self.threshold) {
+                self.notified.remove(&device.address);
+                continue;
+            }
+
+            if !self.notified.insert(device.address.clone()) {
+                continue;
+            }
+
+            if self.sent.contains_key(&device.address) {
+                debug!(
+                    "not notifying about {} again so soon after the last time",
+                    device.name
+                );
+                continue;
+            }
+            if self.interval.is_some() {
+                self.sent.insert(device.address.clone(), Instant::now());
+            }
+
+            debug!("sending a low battery notification for {}", device.name);
+            if let Err(e) = self.notify(device) {
+                warn!("failed to send notification: {e}");
+            }
+        }
+    }
+
+    // https://specifications.freedesktop.org/notification-spec/latest/protocol.html
+    fn notify(&mut self, device: &Device) -> Result<(), dbus::Error> {
+        let conn = match &self.conn {
+            Some(conn) => conn,
+            None => self.conn.insert(Connection::new_session()?),
+        };
+        let proxy = conn.with_proxy(
+            "org.freedesktop.Notifications",
+            "/org/freedesktop/Notifications",
+            self.timeout,
+        );
+
+        let _: (u32,) = proxy.method_call(
+            "org.freedesktop.Notifications",
+            "Notify",
+            (
+                env!("CARGO_BIN_NAME"),
+                0u32,
+                "battery-low",
+                "Low battery",
+                device.long(&Style::default()),
+                Vec::<String>::new(),
+                PropMap::new(),
+                -1i32,
+            ),
+        )?;
+
+        Ok(())
+    }
+}
+
+/// Print a line of output, or with `--output`, replace the file's contents with it.
+fn write_output(output: &str, opt: &Opt) -> Result<(), Box<dyn std::error::Error>> {
+    // The time is added here rather than by render, so that --monitor only sees a change when the
+    // devices change.
+    let output = &with_time(output, opt, SystemTime::now())?;
+    let newline = if opt.no_newline { "" } else { "\n" };
+    // Each line is an update with --monitor and --watch, so they always need the newline when
+    // they're read as they come.
+    let stream_newline = if opt.monitor || opt.watch.is_some() {
+        "\n"
+    } else {
+        newline
+    };
+
+    let Some(path) = &opt.output_file else {
+        let mut stdout = std::io::stdout().lock();
+        write!(stdout, "{output}{stream_newline}")?;
+        stdout.flush()?;
+        return Ok(());
+    };
+
+    if std::fs::metadata(path).is_ok_and(|x| x.file_type().is_fifo()) {
+        write_fifo(path, &format!("{output}{stream_newline}"))
+            .map_err(|e| format!("failed to write {}: {e}", path.display()))?;
+        return Ok(());
+    }
+
+    // Write to a temporary file next to the real one and rename it over the top, so that anything
+    // reading the file never sees it half-written.
+    let mut tmp = path.clone().into_os_string();
+    tmp.push(format!(".{}.tmp", std::process::id()));
+    std::fs::write(&tmp, format!("{output}{newline}"))
+        .and_then(|()| std::fs::rename(&tmp, path))
+        .map_err(|e| {
+            let _ = std::fs::remove_file(&tmp);
+            format!("failed to write {}: {e}", path.display())
+        })?;
+
+    Ok(())
+}
+
+/// Add the time of `now` to the rendered `output`, as `--ndjson` always does and `--timestamp`
+/// asks for, in whatever way suits the output mode.
+fn with_time(
+    output: &str,
+    opt: &Opt,
+    now: SystemTime,
+) -> Result<String, std::time::SystemTimeError> {
+    let since_epoch = now.duration_since(UNIX_EPOCH)?;
+    let time = opt.timestamp.then(|| format_timestamp(now));
+    let prefix_lines = |prefix: &dyn Fn(usize) -> String| {
+        output
+            .lines()
+            .enumerate()
+            .map(|(i, x)| format!("{}{x}", prefix(i)))
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    Ok(match (&opt.output, &time) {
+        (OutputMode::Ndjson, _) => {
+            let time = time
+                .as_ref()
+                .map(|x| format!(",\"time\":{}", json_string(x)))
+                .unwrap_or_default();
+            format!(
+                "{{\"timestamp\":{}{time},\"devices\":{output}}}",
+                since_epoch.as_secs()
+            )
+        }
+        // All start with "{", followed by a newline when pretty-printed.
+        (OutputMode::Json, Some(time)) if opt.json_envelope => {
+            format!("{{\"time\":{},{}", json_string(time), &output[1..])
+        }
+        (OutputMode::JsonPretty, Some(time)) if opt.json_envelope => {
+            format!("{{\n  \"time\": {},{}", json_string(time), &output[1..])
+        }
+        (OutputMode::Waybar, Some(time)) => {
+            format!("{{\"time\":{},{}", json_string(time), &output[1..])
+        }
+        // The time has no commas or tabs to quote.
+        (OutputMode::Csv, Some(time)) => prefix_lines(&|i| match i {
+            0 if opt.csv_header => "time,".to_string(),
+            _ => format!("{time},"),
+        }),
+        (OutputMode::Tsv, Some(time)) => prefix_lines(&|i| match i {
+            0 if opt.csv_header => "time\t".to_string(),
+            _ => format!("{time}\t"),
+        }),
+        // https://prometheus.io/docs/instrumenting/exposition_formats/#text-format-details
+        (OutputMode::Prometheus, Some(_)) => output
+            .lines()
+            .map(|x| {
+                if x.starts_with('#') {
+                    x.to_string()
+                } else {
+                    format!("{x} {}", since_epoch.as_millis())
+                }
+            })
+            .collect::<Vec<_>>()
+            .join("\n"),
+        (
+            OutputMode::Text
+            | OutputMode::Tooltip
+            | OutputMode::Tmux
+            | OutputMode::Polybar
+            | OutputMode::Summary
+            | OutputMode::Raw
+            | OutputMode::Count,
+            Some(time),
+        ) => prefix_lines(&|_| format!("[{time}] ")),
+        _ => output.to_string(),
+    })
+}
+
+/// The named pipe from `--output`, kept open between updates so that its reader doesn't see the
+/// end of the file after each one.
+static FIFO: Mutex<Option<File>> = Mutex::new(None);
+
+/// Write to the named pipe at `path`, opening it (and waiting for a reader) if it isn't open yet,
+/// or again if the last reader went away.
+///
+/// Gives up without writing anything if interrupted while waiting for a reader.
+fn write_fifo(path: &std::path::Path, contents: &str) -> std::io::Result<()> {
+    let mut fifo = FIFO.lock().unwrap_or_else(PoisonError::into_inner);
+    loop {
+        let file = match &mut *fifo {
+            Some(file) => file,
+            None => match open_fifo(path)? {
+                Some(file) => fifo.insert(file),
+                None => return Ok(()),
+            },
+        };
+        match file.write_all(contents.as_bytes()) {
+            Err(e) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                debug!("the reader of {} went away", path.display());
+                *fifo = None;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Open the named pipe at `path` for writing once something opens it for reading, or return
+/// `None` if interrupted first.
+fn open_fifo(path: &std::path::Path) -> std::io::Result<Option<File>> {
+    debug!("waiting for something to read {}", path.display());
+    // A blocking open would be restarted after Ctrl-C, so poll instead: without a reader, a
+    // non-blocking open fails with ENXIO.
+    while !INTERRUPTED.load(Ordering::SeqCst) {
+        match File::options()
+            .write(true)
+            .custom_flags(libc::O_NONBLOCK)
+            .open(path)
+        {
+            Ok(file) => {
+                // Only the open shouldn't block, not the writes.
+                // SAFETY: the descriptor is open for as long as `file` is.
+                unsafe {
+                    let flags = libc::fcntl(file.as_raw_fd(), libc::F_GETFL);
+                    libc::fcntl(file.as_raw_fd(), libc::F_SETFL, flags & !libc::O_NONBLOCK);
+                }
+                return Ok(Some(file));
+            }
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => {
+                std::thread::sleep(Duration::from_millis(100));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(None)
+}
+
+/// Format the devices according to the output mode, without a trailing newline.
+fn render(devices: &[Device], opt: &Opt) -> String {
+    match opt.output {
+        OutputMode::Json if opt.json_envelope => json_envelope(devices, false),
+        OutputMode::JsonPretty if opt.json_envelope => json_envelope(devices, true),
+        OutputMode::Json => json_array(devices, false),
+        OutputMode::JsonPretty => json_array(devices, true),
+        OutputMode::Ndjson => json_array(devices, false),
+        OutputMode::Waybar => Waybar::new(devices, &opt.style).json(),
+        OutputMode::Tooltip => Waybar::new(devices, &opt.style).lines(),
+        OutputMode::Csv => opt
+            .csv_header
+            .then(|| "name,icon,power".to_string())
+            .into_iter()
+            .chain(devices.iter().map(Device::csv_row))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        OutputMode::Tsv => opt
+            .csv_header
+            .then(|| "name\ticon\tpower".to_string())
+            .into_iter()
+            .chain(devices.iter().map(Device::tsv_row))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        OutputMode::Count => devices.len().to_string(),
+        OutputMode::Raw => devices
+            .first()
+            .map(|d| d.power.map_or(UNKNOWN_POWER.to_string(), |x| x.to_string()))
+            .unwrap_or_default(),
+        OutputMode::Prometheus => prometheus_metrics(devices),
+        OutputMode::Summary => summary(devices, &opt.style),
+        OutputMode::Polybar => devices
+            .iter()
+            .map(|device| with_trend(device.polybar(&opt.style), device))
+            .collect::<Vec<_>>()
+            .join(opt.separator.as_deref().unwrap_or(" ")),
+        OutputMode::Tmux => devices
+            .iter()
+            .map(|device| with_trend(device.tmux(&opt.style), device))
+            .collect::<Vec<_>>()
+            .join(opt.separator.as_deref().unwrap_or(" ")),
+        OutputMode::Text => {
+            let separator = match (&opt.separator, &opt.template, opt.fmt()) {
+                (Some(separator), _, _) => separator,
+                (None, None, DeviceFormat::Short) => "  ",
+                (None, _, _) => " ",
+            };
+
+            devices
+                .iter()
+                .map(|device| {
+                    let text = match (&opt.template, opt.fmt_for(device)) {
+                        (Some(template), _) => device.format_with(template, &opt.style),
+                        (None, DeviceFormat::Long) if let Some(template) = &opt.long_template => {
+                            device.format_with(template, &opt.style)
+                        }
+                        (None, DeviceFormat::Long) => device.long(&opt.style),
+                        (None, DeviceFormat::Short) => device.short(&opt.style),
+                        (None, DeviceFormat::Narrow) => device.narrow(&opt.style),
+                        (None, DeviceFormat::IconOnly) => device.icon_only(&opt.style),
+                    };
+                    with_trend(text, device)
+                })
+                .map(|x| match opt.pad_width {
+                    Some(width) => pad(&x, width),
+                    None => x,
+                })
+                .collect::<Vec<_>>()
+                .join(separator)
+        }
+    }
+}
+
+/// Add the device's trend arrow after its text, if it has one.
+fn with_trend(text: String, device: &Device) -> String {
+    match device.trend {
+        Some(trend) => format!("{text} {}", trend.arrow()),
+        None => text,
+    }
+}
+
+enum SortKey {
+    Name,
+    Power,
+    Icon,
+}
+
+impl std::str::FromStr for SortKey {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "name" => Ok(Self::Name),
+            "power" => Ok(Self::Power),
+            "icon" => Ok(Self::Icon),
+            _ => Err("expected one of name, power or icon"),
+        }
+    }
+}
+
+#[derive(Default)]
+enum OutputMode {
+    #[default]
+    Text,
+    Json,
+    JsonPretty,
+    Ndjson,
+    Waybar,
+    Tooltip,
+    Csv,
+    Tsv,
+    Tmux,
+    Polybar,
+    Prometheus,
+    Summary,
+    Raw,
+    Count,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    fn parse(args: &[&str]) -> Result<Opt, OptError> {
+        Opt::from_args(
+            args.iter()
+                .map(|x| x.to_string())
+                .collect::<Vec<_>>()
+                .into_iter(),
+        )
+    }
+
+    fn device(name: &str, icon: &str, power: Option<u64>, connected: bool) -> Device {
+        Device {
+            connected,
+            ..Device::new(name, icon, power)
+        }
+    }
+
+    fn names(devices: &[Device]) -> Vec<&str> {
+        devices.iter().map(|d| d.name.as_str()).collect()
+    }
+
+    /// An empty directory of its own for a test, removed when it's dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            static NEXT: AtomicUsize = AtomicUsize::new(0);
+            let path = std::env::temp_dir().join(format!(
+                "{}-test-{}-{}",
+                env!("CARGO_PKG_NAME"),
+                std::process::id(),
+                NEXT.fetch_add(1, Ordering::Relaxed)
+            ));
+            std::fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        fn path(&self) -> &std::path::Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    impl Opt {
+        /// Load the configuration as if the configuration directory were `dir`, rather than the
+        /// user's own.
+        fn load_config_in(&mut self, dir: &TempDir) -> Result<(), Box<dyn std::error::Error>> {
+            self.load_config_from(
+                Some(&dir.path().join("config.toml")),
+                Some(&dir.path().join("devices")),
+            )
+        }
+    }
+
+    fn devices() -> Vec<Device> {
+        let mut devices = vec![
+            device("Mouse", "input-mouse", Some(40), true),
+            device("Headset", "audio-headset", Some(80), true),
+            device("Keyboard", "input-keyboard", None, true),
+            device("Phone", "phone", Some(10), false),
+        ];
+        for (i, device) in devices.iter_mut().enumerate() {
+            device.address = format!("AA:BB:CC:DD:EE:{i:02X}");
+        }
+        devices
+    }
+
+    #[test]
+    fn parses_combined_short_flags() {
+        let opt = parse(&["-3l", "AA:BB:CC:DD:EE:FF"]).unwrap();
+        assert!(opt.style.i3);
+        assert!(matches!(opt.fmt, Some(DeviceFormat::Long)));
+        assert_eq!(opt.addresses, ["AA:BB:CC:DD:EE:FF"]);
+
+        assert!(matches!(parse(&["-lV"]), Err(OptError::Version)));
+        assert!(matches!(parse(&["-lx"]), Err(OptError::Unknown)));
+    }
+
+    #[test]
+    fn accepts_object_paths() {
+        let opt = parse(&["/org/bluez/hci3/dev_AA_BB_CC_DD_EE_FF", "AA:BB:CC:DD:EE:01"]).unwrap();
+        assert_eq!(
+            opt.device_paths(),
+            [
+                "/org/bluez/hci0/dev_AA_BB_CC_DD_EE_01",
+                "/org/bluez/hci3/dev_AA_BB_CC_DD_EE_FF"
+            ]
+        );
+        assert!(opt.lists_address("AA:BB:CC:DD:EE:FF"));
+        assert!(!opt.lists_address("AA:BB:CC:DD:EE:02"));
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        assert!(matches!(parse(&["--warn"]), Err(OptError::MissingValue(_))));
+        assert!(matches!(parse(&["--warn", "x"]), Err(OptError::Invalid(_))));
+        assert!(matches!(parse(&["z:zz"]), Err(OptError::Invalid(_))));
+        assert!(matches!(
+            parse(&["--watch", "0"]),
+            Err(OptError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn reads_addresses_from_stdin() {
+        let mut opt = parse(&["-", "AA:BB:CC:DD:EE:01"]).unwrap();
+        assert!(opt.stdin);
+        opt.read_addresses("# mine\n\nAA:BB:CC:DD:EE:02\n".as_bytes())
+            .unwrap();
+        assert_eq!(opt.addresses, ["AA:BB:CC:DD:EE:01", "AA:BB:CC:DD:EE:02"]);
+
+        assert!(opt.read_addresses("nope\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn retries_until_success() {
+        let mut attempts = 0;
+        let start = Instant::now();
+        let result = retry(2, "test", || {
+            attempts += 1;
+            if attempts < 2 {
+                Err("not yet")
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(result, Ok(2));
+        // One failure, so one wait of half a second before trying again.
+        assert!(start.elapsed() >= Duration::from_millis(500));
+        assert!(start.elapsed() < Duration::from_millis(1500));
+
+        let mut attempts = 0;
+        let result = retry(0, "test", || {
+            attempts += 1;
+            Err::<(), _>("never")
+        });
+        assert_eq!(result, Err("never"));
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn uses_system_bus_by_default() {
+        assert!(!parse(&[]).unwrap().session_bus);
+        assert!(parse(&["--session-bus"]).unwrap().session_bus);
+    }
+
+    #[test]
+    fn notify_threshold_is_optional() {
+        assert_eq!(parse(&["--notify"]).unwrap().notify, Some(20));
+        assert_eq!(parse(&["--notify", "30"]).unwrap().notify, Some(30));
+
+        let opt = parse(&["--notify=30", "12"]).unwrap();
+        assert_eq!(opt.notify, Some(30));
+        assert_eq!(opt.partial_addresses, ["12"]);
+    }
+
+    #[test]
+    fn holds_back_repeat_notifications() {
+        let mut notifier = Notifier::new(20, Duration::from_secs(1), Some(Duration::from_secs(60)));
+        let mut phone = device("Phone", "phone", Some(10), true);
+        phone.address = "AA:BB:CC:DD:EE:01".to_string();
+        let sent = Instant::now() - Duration::from_secs(30);
+        notifier.sent.insert(phone.address.clone(), sent);
+        notifier.sent.insert(
+            "AA:BB:CC:DD:EE:02".to_string(),
+            sent - Duration::from_secs(60),
+        );
+
+        // Within the interval, so this doesn't try to reach a notification server.
+        notifier.check(std::slice::from_ref(&phone));
+        assert!(notifier.notified.contains(&phone.address));
+        assert_eq!(
+            notifier.sent,
+            HashMap::from([(phone.address.clone(), sent)])
+        );
+    }
+
+    #[test]
+    fn adds_time_to_each_output_mode() {
+        let at = UNIX_EPOCH + Duration::from_secs(100);
+        let timestamped =
+            |args: &[&str], output: &str| with_time(output, &parse(args).unwrap(), at).unwrap();
+        assert_eq!(
+            timestamped(
+                &["--timestamp", "--csv", "--csv-header"],
+                "name,icon,power\nMouse,input-mouse,40"
+            ),
+            "time,name,icon,power\n1970-01-01T00:01:40Z,Mouse,input-mouse,40"
+        );
+        assert_eq!(
+            timestamped(&["--timestamp", "--waybar"], "{\"text\":\"\"}"),
+            "{\"time\":\"1970-01-01T00:01:40Z\",\"text\":\"\"}"
+        );
+        assert_eq!(
+            timestamped(&["--timestamp", "--prometheus"], "# TYPE x gauge\nx 40"),
+            "# TYPE x gauge\nx 40 100000"
+        );
+        assert_eq!(timestamped(&["--json"], "[]"), "[]");
+    }
+
+    #[test]
+    fn requires_named_devices_file() {
+        let dir = TempDir::new();
+        let missing = dir.path().join("missing");
+        let mut opt = parse(&["--devices-file", missing.to_str().unwrap()]).unwrap();
+        assert!(opt.load_config_in(&dir).is_err());
+
+        // Only the default devices file is optional.
+        let mut opt = parse(&[]).unwrap();
+        opt.load_config_in(&dir).unwrap();
+        assert!(opt.addresses.is_empty());
+    }
+
+    #[test]
+    fn keeps_config_when_reload_fails() {
+        let dir = TempDir::new();
+        let missing = dir.path().join("missing");
+        let mut opt = parse(&["--icon-map", missing.to_str().unwrap()]).unwrap();
+        opt.aliases
+            .insert("AA:BB:CC:DD:EE:00".to_string(), "Mouse".to_string());
+        assert!(opt.load_config_in(&dir).is_err());
+        assert_eq!(opt.aliases.get("AA:BB:CC:DD:EE:00").unwrap(), "Mouse");
+    }
+
+    #[test]
+    fn reloads_devices_file() {
+        let dir = TempDir::new();
+        let path = dir.path().join("devices");
+        std::fs::write(&path, "AA:BB:CC:DD:EE:00\n").unwrap();
+        let mut opt = parse(&[]).unwrap();
+        opt.load_config_in(&dir).unwrap();
+        assert_eq!(opt.addresses, ["AA:BB:CC:DD:EE:00"]);
+
+        std::fs::write(&path, "AA:BB:CC:DD:EE:01\n").unwrap();
+        opt.load_config_in(&dir).unwrap();
+        assert_eq!(opt.addresses, ["AA:BB:CC:DD:EE:01"]);
+    }
+
+    #[test]
+    fn reopens_fifo_for_new_reader() {
+        let dir = TempDir::new();
+        let path = dir.path().join("fifo");
+        let c_path = std::ffi::CString::new(path.as_os_str().as_encoded_bytes()).unwrap();
+        // SAFETY: the path is a valid C string.
+        assert_eq!(unsafe { libc::mkfifo(c_path.as_ptr(), 0o600) }, 0);
+
+        let (closed, reader_closed) = mpsc::channel();
+        let reader = std::thread::spawn({
+            let path = path.clone();
+            move || {
+                let read_line = || {
+                    let mut line = String::new();
+                    std::io::BufReader::new(File::open(&path).unwrap())
+                        .read_line(&mut line)
+                        .unwrap();
+                    line
+                };
+                let first = read_line();
+                closed.send(()).unwrap();
+                // Long enough for the next write to find the pipe without a reader.
+                std::thread::sleep(Duration::from_millis(200));
+                (first, read_line())
+            }
+        });
+
+        write_fifo(&path, "one\n").unwrap();
+        reader_closed.recv().unwrap();
+        write_fifo(&path, "two\n").unwrap();
+        *FIFO.lock().unwrap() = None;
+
+        assert_eq!(
+            reader.join().unwrap(),
+            ("one\n".to_string(), "two\n".to_string())
+        );
+    }
+
+    #[test]
+    fn hides_devices_without_battery() {
+        let mut devices = devices();
+        process_devices(&mut devices, &parse(&[]).unwrap());
+        assert_eq!(names(&devices), ["Headset", "Mouse"]);
+    }
+
+    #[test]
+    fn shows_disconnected_devices_without_battery() {
+        let mut devices = devices();
+        process_devices(&mut devices, &parse(&["--show-disconnected"]).unwrap());
+        assert_eq!(names(&devices), ["Headset", "Mouse", "Phone"]);
+        assert_eq!(devices[2].power, None);
+
+        let mut devices = self::devices();
+        process_devices(&mut devices, &parse(&["--all"]).unwrap());
+        assert_eq!(names(&devices), ["Headset", "Mouse", "Phone"]);
+        assert_eq!(devices[2].power, Some(10));
+    }
+
+    #[test]
+    fn filters_by_threshold() {
+        let mut devices = devices();
+        process_devices(&mut devices, &parse(&["--only-below", "50"]).unwrap());
+        assert_eq!(names(&devices), ["Mouse"]);
+    }
+
+    #[test]
+    fn sorts_by_key() {
+        let mut devices = devices();
+        process_devices(&mut devices, &parse(&["--sort", "power"]).unwrap());
+        assert_eq!(names(&devices), ["Mouse", "Headset"]);
+
+        let mut devices = self::devices();
+        process_devices(
+            &mut devices,
+            &parse(&["--sort", "icon", "--reverse"]).unwrap(),
+        );
+        assert_eq!(names(&devices), ["Mouse", "Headset"]);
+    }
+
+    #[test]
+    fn shows_lowest() {
+        let mut devices = devices();
+        devices[1].power = Some(40);
+        process_devices(&mut devices, &parse(&["--lowest", "--reverse"]).unwrap());
+        assert_eq!(names(&devices), ["Mouse"]);
+
+        let mut devices = self::devices();
+        process_devices(
+            &mut devices,
+            &parse(&["--lowest", "--only-below", "5"]).unwrap(),
+        );
+        assert!(devices.is_empty());
+    }
+
+    #[test]
+    fn pins_order() {
+        let mut devices = devices();
+        let mut opt = parse(&["--pin-order", "--all"]).unwrap();
+        opt.order = vec![devices[3].address.clone(), devices[0].address.clone()];
+        process_devices(&mut devices, &opt);
+        assert_eq!(names(&devices), ["Phone", "Mouse", "Headset"]);
+    }
+
+    #[test]
+    fn preserves_arg_order() {
+        let mut devices = devices();
+        let opt = parse(&[
+            "--preserve-arg-order",
+            "AA:BB:CC:DD:EE:01",
+            "/org/bluez/hci0/dev_AA_BB_CC_DD_EE_00",
+        ])
+        .unwrap();
+        process_devices(&mut devices, &opt);
+        assert_eq!(names(&devices), ["Headset", "Mouse"]);
+    }
+
+    #[test]
+    fn follows_trends() {
+        let mut trends = Trends::default();
+        let mut devices = devices();
+        let opt = parse(&["-s"]).unwrap();
+        trends.apply(&mut devices);
+        assert_eq!(devices[0].trend, Some(Trend::Steady));
+
+        devices[0].power = Some(45);
+        trends.apply(&mut devices);
+        devices[1].power = Some(70);
+        trends.apply(&mut devices);
+        assert_eq!(devices[0].trend, Some(Trend::Rising));
+        assert_eq!(devices[1].trend, Some(Trend::Falling));
+        assert_eq!(devices[2].trend, None);
+
+        devices.truncate(2);
+        assert_eq!(render(&devices, &opt), "Mouse 45% ↑  Headset 70% ↓");
+    }
+
+    #[test]
+    fn applies_aliases() {
+        let mut devices = devices();
+        let alias = format!("{}=Cans", devices[1].address.to_lowercase());
+        process_devices(&mut devices, &parse(&["--alias", &alias]).unwrap());
+        assert_eq!(names(&devices), ["Cans", "Mouse"]);
+
+        assert!(parse(&["--alias", "AA:BB:CC:DD:EE=Cans"]).is_err());
+        assert!(parse(&["--alias", "Cans"]).is_err());
+    }
+
+    #[test]
+    fn matches_names_and_addresses() {
+        let mut devices = devices();
+        let opt = parse(&["--name", "MOU", "--name", "cans", "AA:BB:CC:DD:EE:01"]).unwrap();
+        process_devices(&mut devices, &opt);
+        assert_eq!(names(&devices), ["Headset", "Mouse"]);
+    }
+
+    #[test]
+    fn renders_with_separator() {
+        let mut devices = devices();
+        let opt = parse(&["-s", "--separator", "\\n"]).unwrap();
+        process_devices(&mut devices, &opt);
+        assert_eq!(render(&devices, &opt), "Headset 80%\nMouse 40%");
+    }
+
+    #[test]
+    fn renders_long_template() {
+        let mut devices = devices();
+        let opt = parse(&["-l", "--long-template", "{name}: {power}%"]).unwrap();
+        process_devices(&mut devices, &opt);
+        assert_eq!(render(&devices, &opt), "Headset: 80% Mouse: 40%");
+    }
 }